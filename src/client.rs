@@ -0,0 +1,121 @@
+//! The command-line client, which sends commands to the orchestrator running in the current
+//! directory through its control socket (see `formica.sock`).
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// How often `shutdown --wait` checks whether the orchestrator has exited.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+const USAGE: &str = "Usage:
+  formica-ci                                   run the orchestrator
+  formica-ci trigger <job name> [KEY=value...]  queue a build of a job
+  formica-ci status                            list the running and waiting builds
+  formica-ci logs [--stderr] <job name>[/<build number>]
+                                               print the log of a build (the last one of the
+                                               job by default), following it while it runs
+  formica-ci cancel <job name>[/<build number>]
+                                               cancel a running build, or remove the
+                                               builds of the job waiting for a free slot
+  formica-ci shutdown [--now] [--wait]         shut down once the running builds have
+                                               finished (--now aborts them), and wait for
+                                               the orchestrator to exit (--wait)";
+
+/// Runs a client command, returning the exit code of the process.
+pub fn run(arguments: &[String]) -> i32 {
+    let arguments: Vec<&str> = arguments.iter().map(String::as_str).collect();
+    let (request, wait_for_exit) = match arguments.as_slice() {
+        ["trigger", job_name, parameters @ ..] => {
+            if let Some(parameter) = parameters.iter().find(|parameter| !parameter.contains('=')) {
+                eprintln!("Expected a KEY=value parameter, got {}", parameter);
+                return exitcode::USAGE;
+            }
+            let mut request = format!("TRIGGER {}\n", job_name);
+            for parameter in parameters {
+                request.push_str(parameter);
+                request.push('\n');
+            }
+            (request, false)
+        }
+        ["status"] => ("STATUS".to_string(), false),
+        ["logs", build] => (format!("LOG stdout {}", build), false),
+        ["logs", "--stderr", build] | ["logs", build, "--stderr"] => {
+            (format!("LOG stderr {}", build), false)
+        }
+        ["cancel", build_id] => (format!("CANCEL {}", build_id), false),
+        ["shutdown", options @ ..]
+            if options
+                .iter()
+                .all(|option| *option == "--now" || *option == "--wait") =>
+        {
+            let mode = if options.contains(&"--now") {
+                "immediate"
+            } else {
+                "slow"
+            };
+            (format!("SHUTDOWN {}", mode), options.contains(&"--wait"))
+        }
+        ["help"] | ["--help"] | ["-h"] => {
+            println!("{}", USAGE);
+            return exitcode::OK;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            return exitcode::USAGE;
+        }
+    };
+    let socket_path = crate::job_runner::control_socket();
+    let stream = match UnixStream::connect(&socket_path) {
+        Ok(stream) => stream,
+        Err(connect_err) => {
+            eprintln!(
+                "Could not reach Formica CI through {} (is it running in this directory?): {}",
+                socket_path.display(),
+                connect_err
+            );
+            return exitcode::UNAVAILABLE;
+        }
+    };
+    let exit_code = match send_request(stream, &request) {
+        Ok(exit_code) => exit_code,
+        Err(connection_err) => {
+            eprintln!("Lost the connection to Formica CI: {}", connection_err);
+            return exitcode::IOERR;
+        }
+    };
+    if wait_for_exit && exit_code == exitcode::OK {
+        wait_for_shutdown(&socket_path);
+    }
+    exit_code
+}
+
+/// Sends the request, and prints the output of the command as it comes.
+fn send_request(mut stream: UnixStream, request: &str) -> io::Result<i32> {
+    stream.write_all(request.as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = BufReader::new(stream);
+    let mut status_line = String::new();
+    response.read_line(&mut status_line)?;
+    let status_line = status_line.trim_end();
+    if status_line == "OK" {
+        io::copy(&mut response, &mut io::stdout())?;
+        Ok(exitcode::OK)
+    } else if let Some(message) = status_line.strip_prefix("ERROR ") {
+        eprintln!("Error: {}", message);
+        Ok(exitcode::DATAERR)
+    } else {
+        eprintln!("Unexpected answer from Formica CI: {}", status_line);
+        Ok(exitcode::PROTOCOL)
+    }
+}
+
+/// Waits until the orchestrator no longer accepts connections, i.e. it has exited.
+fn wait_for_shutdown(socket_path: &Path) {
+    while UnixStream::connect(socket_path).is_ok() {
+        thread::sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+}
@@ -1,16 +1,41 @@
+mod build;
+#[cfg(unix)]
+mod control;
+#[cfg(feature = "http")]
+mod hook;
+#[cfg(feature = "http")]
+mod http;
+mod job_settings;
+mod poll;
+mod pool;
+mod post_build;
+mod protocol;
+mod queue;
+mod schedule;
 mod script;
+mod settings;
 
+use build::{BuildLogs, BuildRecord, BuildStatus};
+use job_settings::{JobSettings, JOB_SETTINGS_FILE};
+use pool::{Pool, POOLS_DIR};
+use protocol::AgentConnection;
+use queue::JobTrigger;
+use schedule::ScheduleEntry;
 use script::ScriptErrorKind::{NoScriptFound, TooManyScriptsFound};
+use settings::{Settings, SettingsError, SharedSettings};
 
-use crossbeam_channel::{bounded, select, unbounded, Receiver, Sender};
+use crossbeam_channel::{after, at, bounded, never, select, unbounded, Receiver, Sender};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::env;
+use std::fmt;
 use std::fs;
-use std::io::Write;
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::iter::FromIterator;
-use std::path::{Path, PathBuf};
-use std::process::Output;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, ExitStatus, Output};
+use std::sync::{Arc, RwLock};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 use walkdir::{DirEntry, WalkDir};
 
 const CONFIG: &str = "formica_conf";
@@ -18,6 +43,16 @@ pub const CONFIG_INIT_PREFIX: &str = "config_init";
 pub const QUEUE_DIR: &str = "queue";
 pub const UPDATE: &str = "update";
 pub const AGENT_INIT: &str = "agent_init";
+pub const STEP_PREFIX: &str = "step_";
+pub const ARTIFACTS: &str = "artifacts";
+pub const BUILD_DIR: &str = "formica_builds";
+pub const AGENT_CLEANUP: &str = "agent_cleanup";
+pub const CONTROL_SOCKET: &str = "formica.sock";
+
+const PROCESS_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long the worker of a timed out build has to exit after SIGTERM, before its agent is
+/// cleaned up and it is killed.
+const TERMINATION_GRACE_PERIOD: Duration = Duration::from_secs(10);
 
 fn create_slow_shutdown_channel() -> (Sender<()>, Receiver<()>) {
     bounded(1)
@@ -39,25 +74,136 @@ pub fn initialize() -> Result<ShutdownNotifiers, InitError> {
         config_fetch()?;
     }
     initial_config_update()?;
+    let settings = Settings::load(config_dir).map_err(|settings_err| InitError {
+        kind: InitErrorKind::InvalidSettings(settings_err),
+    })?;
+    settings.apply_log_level();
+    let settings: SharedSettings = Arc::new(RwLock::new(settings));
     let (slow_shutdown_notifier, slow_shutdown_listener) = create_slow_shutdown_channel();
     let (immediate_shutdown_notifier, immediate_shutdown_listener) =
         create_immediate_shutdown_channel();
     let (force_terminate_notifier, force_terminate_listener) = create_force_termination_channel();
 
-    launch_background_updater();
-    start_orchestrator(ShutdownListeners {
-        slow_shutdown: slow_shutdown_listener,
-        immediate_shutdown: immediate_shutdown_listener,
-        force_termination: force_terminate_listener,
+    let (graceful_shutdown_notifier, graceful_shutdown_listener) = unbounded();
+    let (shutdown_complete_notifier, shutdown_complete_listener) = bounded(1);
+    // requests made while an update is pending are merged into it
+    let (config_update_notifier, config_update_listener) = bounded(1);
+    let (state_dump_notifier, state_dump_listener) = unbounded();
+
+    let jobs = find_jobs().map_err(|find_err| match find_err.kind {
+        JobRunnerErrorKind::NoJobsFound => InitError {
+            kind: InitErrorKind::NoJobsFound,
+        },
     })?;
+    for job in jobs.iter() {
+        println!(
+            "FOUND JOB {} AT {}",
+            job.name,
+            job.root_folder.to_str().unwrap()
+        );
+    }
+    let jobs: SharedJobs = Arc::new(RwLock::new(jobs.into_iter().map(Arc::new).collect()));
+
+    let (request_notifier, request_listener) = unbounded();
+    let trigger_listener =
+        build_job_trigger_channel(settings.clone(), jobs.clone(), request_notifier.clone());
+
+    // the configuration was just updated, before reading the settings and the jobs
+    let updater_status: SharedUpdaterStatus = Arc::new(RwLock::new(UpdaterStatus {
+        last_run: Some(SystemTime::now()),
+        last_success: Some(SystemTime::now()),
+        ..UpdaterStatus::default()
+    }));
+
+    launch_background_updater(
+        settings.clone(),
+        jobs.clone(),
+        updater_status.clone(),
+        config_update_listener,
+    );
+    start_http_api(&settings, &jobs, &updater_status, request_notifier.clone())?;
+    start_control_server(
+        &settings,
+        &jobs,
+        request_notifier,
+        &slow_shutdown_notifier,
+        &immediate_shutdown_notifier,
+    )?;
+    // no other orchestrator is listening on the control socket, so no build is running
+    let build_dir = settings.read().unwrap().build_dir.clone();
+    for build_id in build::abort_stale_builds(&build_dir) {
+        warn!(
+            "Build {} was left running by the previous run, recording it as ABORTED",
+            build_id
+        );
+    }
+    start_orchestrator(
+        ShutdownListeners {
+            slow_shutdown: slow_shutdown_listener,
+            immediate_shutdown: immediate_shutdown_listener,
+            force_termination: force_terminate_listener,
+            graceful_shutdown: graceful_shutdown_listener,
+            state_dump: state_dump_listener,
+        },
+        trigger_listener,
+        request_listener,
+        shutdown_complete_notifier,
+        settings,
+        jobs,
+    )?;
 
     Ok(ShutdownNotifiers {
         slow_shutdown: slow_shutdown_notifier,
         immediate_shutdown: immediate_shutdown_notifier,
         force_termination: force_terminate_notifier,
+        graceful_shutdown: graceful_shutdown_notifier,
+        shutdown_complete: shutdown_complete_listener,
+        config_update: config_update_notifier,
+        state_dump: state_dump_notifier,
+    })
+}
+
+/// Starts listening for the commands of the command-line client.
+#[cfg(unix)]
+fn start_control_server(
+    settings: &SharedSettings,
+    jobs: &SharedJobs,
+    request_notifier: Sender<OrchestratorRequest>,
+    slow_shutdown: &Sender<()>,
+    immediate_shutdown: &Sender<()>,
+) -> Result<(), InitError> {
+    let socket_path = settings.read().unwrap().control_socket.clone();
+    let context = control::ControlContext {
+        settings: settings.clone(),
+        jobs: jobs.clone(),
+        request_notifier,
+        slow_shutdown: slow_shutdown.clone(),
+        immediate_shutdown: immediate_shutdown.clone(),
+    };
+    control::launch_control_server(&socket_path, context).map_err(|socket_err| InitError {
+        kind: InitErrorKind::ControlSocketError(socket_path, socket_err),
     })
 }
 
+#[cfg(not(unix))]
+fn start_control_server(
+    _settings: &SharedSettings,
+    _jobs: &SharedJobs,
+    _request_notifier: Sender<OrchestratorRequest>,
+    _slow_shutdown: &Sender<()>,
+    _immediate_shutdown: &Sender<()>,
+) -> Result<(), InitError> {
+    debug!("The command-line client is only supported on Unix");
+    Ok(())
+}
+
+/// The socket the command-line client connects to, as set in the settings file.
+pub fn control_socket() -> PathBuf {
+    Settings::load(Path::new(CONFIG))
+        .unwrap_or_default()
+        .control_socket
+}
+
 fn update_config() -> Result<std::io::Result<Output>, script::ScriptError> {
     let config_dir = Path::new(CONFIG);
     let update_script_result = script::find_script(&config_dir.to_path_buf(), UPDATE);
@@ -70,51 +216,1025 @@ fn update_config() -> Result<std::io::Result<Output>, script::ScriptError> {
     }
 }
 
-fn start_orchestrator(shutdown_listeners: ShutdownListeners) -> Result<(), InitError> {
-    let jobs = find_jobs().unwrap();
-    for job in jobs.iter() {
-        println!("FOUND JOB AT {}", job.root_folder.to_str().unwrap());
+/// Starts the HTTP API if an address is set for it.
+#[cfg(feature = "http")]
+fn start_http_api(
+    settings: &SharedSettings,
+    jobs: &SharedJobs,
+    updater_status: &SharedUpdaterStatus,
+    request_notifier: Sender<OrchestratorRequest>,
+) -> Result<(), InitError> {
+    let http_address = match settings.read().unwrap().http_address.clone() {
+        Some(http_address) => http_address,
+        None => return Ok(()),
+    };
+    http::launch_http_server(
+        &http_address,
+        settings.clone(),
+        jobs.clone(),
+        updater_status.clone(),
+        request_notifier,
+    )
+    .map_err(|server_err| InitError {
+        kind: InitErrorKind::HttpServerError(http_address, server_err.to_string()),
+    })
+}
+
+#[cfg(not(feature = "http"))]
+fn start_http_api(
+    settings: &SharedSettings,
+    _jobs: &SharedJobs,
+    _updater_status: &SharedUpdaterStatus,
+    _request_notifier: Sender<OrchestratorRequest>,
+) -> Result<(), InitError> {
+    if settings.read().unwrap().http_address.is_some() {
+        warn!("http_address is set, but this build of Formica CI does not include the HTTP API (the http feature)");
     }
-    launch_job_queue_poller();
-    let job_listener = build_job_queue_channel()?;
+    Ok(())
+}
+
+fn start_orchestrator(
+    shutdown_listeners: ShutdownListeners,
+    job_listener: Receiver<JobTrigger>,
+    mut request_listener: Receiver<OrchestratorRequest>,
+    shutdown_complete: Sender<()>,
+    settings: SharedSettings,
+    jobs: SharedJobs,
+) -> Result<(), InitError> {
     thread::spawn(move || {
         let mut slow_shutdown = false;
-        let mut job_list = jobs;
+        let mut running_builds: HashMap<String, RunningBuild> = HashMap::new();
+        // finished builds running their post-build scripts, which no longer hold a slot
+        let mut finishing_builds: HashMap<String, Sender<BuildControl>> = HashMap::new();
+        // sent to the post-build scripts, including those of the builds finishing later,
+        // once a shutdown no longer waits for them
+        let mut post_build_control: Option<BuildControl> = None;
+        // builds waiting for a free slot, by decreasing priority and then in order of arrival
+        let mut pending_builds: VecDeque<(Arc<Job>, JobTrigger)> = VecDeque::new();
+        // builds that failed because of their agent, waiting to be queued again
+        let mut scheduled_retries: Vec<(Instant, JobTrigger)> = Vec::new();
+        let (finished_notifier, finished_listener) = unbounded::<FinishedBuild>();
+        let (post_build_notifier, post_build_listener) = unbounded::<String>();
+        let mut graceful_shutdown = false;
+        let mut shutdown_deadline: Receiver<Instant> = never();
         loop {
+            let next_retry = scheduled_retries
+                .iter()
+                .map(|(due, _)| *due)
+                .min()
+                .map(at)
+                .unwrap_or_else(never);
             select! {
-                recv(job_listener) -> job_name => {
-                    if !slow_shutdown {
-                        let job_to_run = job_list.iter().filter(|job| job.root_folder//
-                            .file_name().expect("Failed to read job folder name!")//
-                            .to_str().expect("Failed to convert job folder name to Unicode!")//
-                            .contains(job_name.as_ref().unwrap())
-                        ).next().unwrap();
-                        thread::spawn(move || {
-                            run_job(&job_to_run);
+                recv(job_listener) -> trigger => {
+                    let trigger: JobTrigger = match trigger {
+                        Ok(trigger) => trigger,
+                        Err(_) => break,
+                    };
+                    if slow_shutdown {
+                        // the claimed trigger is kept, so it will be run after the next start
+                        info!("Not accepting job {}, shutdown in progress", trigger.job_name);
+                        continue;
+                    }
+                    // the build keeps this definition of the job, even if the jobs are reloaded meanwhile
+                    let job_to_run = match find_job(&jobs, &trigger.job_name) {
+                        Some(job) => job,
+                        None => {
+                            error!("Rejecting trigger for unknown job {}", trigger.job_name);
+                            trigger.reject();
+                            continue;
+                        }
+                    };
+                    queue_build(job_to_run, trigger, &mut pending_builds, &settings, &running_builds);
+                }
+                recv(request_listener) -> request => match request {
+                    Ok(OrchestratorRequest::QueueBuild { trigger, reply }) => {
+                        let queued = match find_job(&jobs, &trigger.job_name) {
+                            _ if slow_shutdown => {
+                                info!("Not accepting job {}, shutdown in progress", trigger.job_name);
+                                false
+                            }
+                            Some(job_to_run) => {
+                                queue_build(job_to_run, trigger, &mut pending_builds, &settings, &running_builds);
+                                true
+                            }
+                            None => {
+                                error!("Rejecting trigger for unknown job {}", trigger.job_name);
+                                false
+                            }
+                        };
+                        let _ = reply.send(queued);
+                    }
+                    Ok(OrchestratorRequest::CancelBuild { build_id, reply }) => {
+                        let cancelled = match running_builds.get(&build_id) {
+                            Some(running_build) => {
+                                info!("Cancelling build {}", build_id);
+                                running_build.control.send(BuildControl::Cancel).is_ok()
+                            }
+                            None => false,
+                        };
+                        let _ = reply.send(cancelled);
+                    }
+                    Ok(OrchestratorRequest::CancelWaitingBuilds { job_name, reply }) => {
+                        let waiting_builds = pending_builds.len() + scheduled_retries.len();
+                        scheduled_retries.retain(|(_, trigger)| {
+                            if trigger.job_name != job_name {
+                                return true;
+                            }
+                            info!("Removing the retry of {} (triggered by {})", job_name, trigger.source);
+                            false
+                        });
+                        pending_builds.retain(|(job, trigger)| {
+                            if job.name != job_name {
+                                return true;
+                            }
+                            info!("Removing the waiting build of {} (triggered by {})", job_name, trigger.source);
+                            // the build will not be run, so its claimed trigger file can go
+                            trigger.acknowledge();
+                            false
+                        });
+                        let _ = reply.send(waiting_builds - pending_builds.len() - scheduled_retries.len());
+                    }
+                    Ok(OrchestratorRequest::ListBuilds { reply }) => {
+                        let mut running_builds: Vec<String> = running_builds.keys().cloned().collect();
+                        running_builds.sort();
+                        let _ = reply.send(OrchestratorStatus {
+                            running_builds,
+                            pending_builds: pending_builds
+                                .iter()
+                                .map(|(job, trigger)| (job.name.clone(), trigger.source.clone()))
+                                .chain(scheduled_retries.iter().map(|(_, trigger)| {
+                                    (trigger.job_name.clone(), trigger.source.clone())
+                                }))
+                                .collect(),
                         });
                     }
+                    // nothing can make requests anymore
+                    Err(_) => request_listener = never(),
+                },
+                recv(finished_listener) -> finished_build => {
+                    if let Ok(finished_build) = finished_build {
+                        if let Some(running_build) = running_builds.remove(&finished_build.build_id) {
+                            if let Some(control) = post_build_control {
+                                let _ = running_build.control.send(control);
+                            }
+                            finishing_builds.insert(finished_build.build_id, running_build.control);
+                        }
+                        if let Some((trigger, delay)) = finished_build.retry {
+                            scheduled_retries.push((Instant::now() + delay, trigger));
+                        }
+                    }
+                }
+                recv(post_build_listener) -> build_id => {
+                    if let Ok(build_id) = build_id {
+                        finishing_builds.remove(&build_id);
+                    }
+                }
+                recv(next_retry) -> _ => {
+                    let now = Instant::now();
+                    let (due_retries, later_retries): (Vec<_>, Vec<_>) = scheduled_retries
+                        .into_iter()
+                        .partition(|(due, _)| *due <= now);
+                    scheduled_retries = later_retries;
+                    for (_, trigger) in due_retries {
+                        // like any trigger, the retry runs with the current definition of the job
+                        match find_job(&jobs, &trigger.job_name) {
+                            Some(job) => queue_build(job, trigger, &mut pending_builds, &settings, &running_builds),
+                            None => error!("Not retrying the build of {}: the job has been removed", trigger.job_name),
+                        }
+                    }
                 }
                 recv(shutdown_listeners.slow_shutdown) -> _ => {
                     slow_shutdown = true;
                 }
+                recv(shutdown_listeners.immediate_shutdown) -> _ => {
+                    slow_shutdown = true;
+                    abort_builds(&running_builds, &finishing_builds, &mut post_build_control, BuildControl::Abort);
+                }
+                recv(shutdown_listeners.graceful_shutdown) -> _ => {
+                    if graceful_shutdown {
+                        info!("Shutdown requested again, aborting the running builds...");
+                        shutdown_deadline = after(Duration::from_secs(0));
+                    } else {
+                        let shutdown_timeout = settings.read().unwrap().shutdown_timeout;
+                        info!(
+                            "Starting graceful shutdown: no more jobs will be accepted, and the builds still running in {}s will be aborted...",
+                            shutdown_timeout.as_secs()
+                        );
+                        graceful_shutdown = true;
+                        shutdown_deadline = after(shutdown_timeout);
+                    }
+                    slow_shutdown = true;
+                }
+                recv(shutdown_deadline) -> _ => {
+                    shutdown_deadline = never();
+                    if !running_builds.is_empty() {
+                        warn!("Shutdown timeout reached, aborting the running builds...");
+                    }
+                    abort_builds(&running_builds, &finishing_builds, &mut post_build_control, BuildControl::Abort);
+                }
+                recv(shutdown_listeners.state_dump) -> _ => {
+                    info!(
+                        "{} build(s) running, {} waiting for a free slot{}",
+                        running_builds.len(),
+                        pending_builds.len(),
+                        if slow_shutdown { ", shutting down" } else { "" }
+                    );
+                    let mut running_build_ids: Vec<&String> = running_builds.keys().collect();
+                    running_build_ids.sort();
+                    for build_id in running_build_ids {
+                        match &running_builds[build_id].pool_slot {
+                            Some((pool_name, slot)) => {
+                                info!("Running: {} (pool {}, slot {})", build_id, pool_name, slot)
+                            }
+                            None => info!("Running: {}", build_id),
+                        }
+                    }
+                    let mut finishing_build_ids: Vec<&String> = finishing_builds.keys().collect();
+                    finishing_build_ids.sort();
+                    for build_id in finishing_build_ids {
+                        info!("Running the post-build scripts: {}", build_id);
+                    }
+                    for (job, trigger) in pending_builds.iter() {
+                        info!("Waiting: {} (triggered by {})", job.name, trigger.source);
+                    }
+                    for (due, trigger) in scheduled_retries.iter() {
+                        info!(
+                            "Waiting: {} (triggered by {}, queued in {}s)",
+                            trigger.job_name,
+                            trigger.source,
+                            due.saturating_duration_since(Instant::now()).as_secs()
+                        );
+                    }
+                }
+                recv(shutdown_listeners.force_termination) -> _ => {
+                    slow_shutdown = true;
+                    abort_builds(&running_builds, &finishing_builds, &mut post_build_control, BuildControl::Kill);
+                }
+            }
+            if slow_shutdown {
+                // the claimed triggers are kept, so they will be run after the next start
+                pending_builds.clear();
+                for (_, trigger) in scheduled_retries.drain(..) {
+                    info!(
+                        "Not retrying the build of {}, shutdown in progress",
+                        trigger.job_name
+                    );
+                }
+            }
+            while has_free_slot(&settings, &running_builds) {
+                // the first build that neither its job nor its pool holds up, so that e.g.
+                // a job limited to one build at a time does not hold up the others
+                let next_build = pending_builds
+                    .iter()
+                    .position(|(job, _)| can_start(job, &running_builds));
+                let (job_to_run, trigger) = match next_build {
+                    Some(index) => pending_builds.remove(index).unwrap(),
+                    None => break,
+                };
+                let job_name = job_to_run.name.clone();
+                let pool_slot = job_to_run.pool.as_ref().map(|pool| {
+                    let slot = free_pool_slot(pool, &running_builds).unwrap();
+                    (pool.name.clone(), slot)
+                });
+                let build_dir = settings.read().unwrap().build_dir.clone();
+                if let Some((build_id, control)) = start_build(
+                    job_to_run,
+                    trigger,
+                    &build_dir,
+                    pool_slot.as_ref().map(|(_, slot)| *slot),
+                    finished_notifier.clone(),
+                    post_build_notifier.clone(),
+                ) {
+                    running_builds.insert(
+                        build_id,
+                        RunningBuild {
+                            job_name,
+                            pool_slot,
+                            control,
+                        },
+                    );
+                }
+            }
+            if slow_shutdown && running_builds.is_empty() && finishing_builds.is_empty() {
+                info!("All builds have finished");
+                let _ = shutdown_complete.send(());
+                break;
             }
         }
     });
     Ok(())
 }
 
-fn run_job(job_to_run: &Job) {
-    let agent_init_script = script::find_script(&job_to_run.root_folder, AGENT_INIT)
-        .expect("Could not find agent_init script!");
-    let worker = script::spawn_worker_script(&job_to_run.root_folder, &agent_init_script);
-    // TODO: better error handling / reporting?
-    let mut worker = worker.expect("Error when spawning worker");
-    let mut worker_input = worker.stdin.take().unwrap();
-    worker_input.write_all("ls\n".as_bytes());
-    let worker_output = worker.stdout.take().unwrap();
-    worker
-        .wait()
-        .expect("Failed to wait for process to terminate!");
+/// Stops the running builds and the post-build scripts of the finished ones with `control`
+/// (`Abort` or `Kill`), which the post-build scripts of the builds finishing later get too.
+fn abort_builds(
+    running_builds: &HashMap<String, RunningBuild>,
+    finishing_builds: &HashMap<String, Sender<BuildControl>>,
+    post_build_control: &mut Option<BuildControl>,
+    control: BuildControl,
+) {
+    for running_build in running_builds.values() {
+        let _ = running_build.control.send(control);
+    }
+    // a forced termination is not turned back into an abort
+    if *post_build_control != Some(BuildControl::Kill) {
+        *post_build_control = Some(control);
+    }
+    for finishing_build in finishing_builds.values() {
+        let _ = finishing_build.send(control);
+    }
+}
+
+/// Adds a build of the job to the builds waiting for a free slot, after the builds of the
+/// same or higher priority. With the `coalesce` job setting, the trigger replaces the one
+/// of the build of the job that is already waiting, if any.
+fn queue_build(
+    job: Arc<Job>,
+    trigger: JobTrigger,
+    pending_builds: &mut VecDeque<(Arc<Job>, JobTrigger)>,
+    settings: &SharedSettings,
+    running_builds: &HashMap<String, RunningBuild>,
+) {
+    if job.settings.coalesce {
+        let waiting_build = pending_builds
+            .iter_mut()
+            .find(|(pending_job, _)| pending_job.name == job.name);
+        if let Some((pending_job, pending_trigger)) = waiting_build {
+            info!(
+                "Merging the trigger from {} into the build of {} waiting for a free slot",
+                trigger.source, job.name
+            );
+            // the replaced trigger will not be run, so its claimed file can go
+            pending_trigger.acknowledge();
+            *pending_job = job;
+            *pending_trigger = trigger;
+            return;
+        }
+    }
+    // pending builds are started as soon as possible, so only a limit can hold this one up
+    if !has_free_slot(settings, running_builds) || !can_start(&job, running_builds) {
+        info!("Build of {} is waiting for a free slot", job.name);
+    }
+    let index = pending_builds
+        .iter()
+        .position(|(pending_job, _)| pending_job.settings.priority < job.settings.priority)
+        .unwrap_or(pending_builds.len());
+    pending_builds.insert(index, (job, trigger));
+}
+
+fn has_free_slot(
+    settings: &SharedSettings,
+    running_builds: &HashMap<String, RunningBuild>,
+) -> bool {
+    match settings.read().unwrap().max_concurrent_jobs {
+        Some(max_concurrent_jobs) => running_builds.len() < max_concurrent_jobs,
+        None => true,
+    }
+}
+
+/// Whether the limit of the job and the capacity of its pool (if any) let a build of the
+/// job start, regardless of `max_concurrent_jobs`.
+// `Option::is_none_or` would need Rust 1.82
+#[allow(clippy::unnecessary_map_or)]
+fn can_start(job: &Job, running_builds: &HashMap<String, RunningBuild>) -> bool {
+    let below_job_limit = match job.settings.max_concurrent_builds {
+        Some(max_concurrent_builds) => {
+            running_builds
+                .values()
+                .filter(|running_build| running_build.job_name == job.name)
+                .count()
+                < max_concurrent_builds
+        }
+        None => true,
+    };
+    below_job_limit
+        && job
+            .pool
+            .as_ref()
+            .map_or(true, |pool| free_pool_slot(pool, running_builds).is_some())
+}
+
+/// The lowest slot of the pool that no running build uses, if any.
+fn free_pool_slot(pool: &Pool, running_builds: &HashMap<String, RunningBuild>) -> Option<usize> {
+    (0..pool.capacity).find(|slot| {
+        !running_builds
+            .values()
+            .filter_map(|running_build| running_build.pool_slot.as_ref())
+            .any(|(pool_name, used_slot)| *pool_name == pool.name && used_slot == slot)
+    })
+}
+
+/// Creates the record of a new build of the job and runs it in a separate thread, which
+/// notifies `finished_notifier` when the build is done, and then `post_build_notifier` once
+/// its post-build scripts are done too. Returns the build id along with the channel
+/// controlling the build and its post-build scripts, or `None` if the build could not be started.
+fn start_build(
+    job_to_run: Arc<Job>,
+    trigger: JobTrigger,
+    build_dir: &Path,
+    pool_slot: Option<usize>,
+    finished_notifier: Sender<FinishedBuild>,
+    post_build_notifier: Sender<String>,
+) -> Option<(String, Sender<BuildControl>)> {
+    let mut build = match BuildRecord::create(build_dir, &job_to_run.name, &trigger.source) {
+        Ok(build) => build,
+        Err(create_err) => {
+            // the claimed trigger is kept, so it will be run after the next start
+            error!(
+                "Failed to create the build folder of {}: {}",
+                job_to_run.name, create_err
+            );
+            return None;
+        }
+    };
+    trigger.acknowledge();
+    let build_id = build.id();
+    info!("Starting build {}", build_id);
+    let (control_sender, control_receiver) = unbounded();
+    let timeout_notifier = control_sender.clone();
+    let build_root = build_dir.to_path_buf();
+    thread::spawn(move || {
+        let outcome = run_job(
+            &job_to_run,
+            &trigger,
+            &build,
+            pool_slot,
+            control_receiver.clone(),
+            timeout_notifier,
+        );
+        info!(
+            "Build {} finished with status {}",
+            build.id(),
+            outcome.status
+        );
+        if !outcome.infrastructure_errors.is_empty() {
+            build.infrastructure_error = Some(outcome.infrastructure_errors.join("; "));
+        }
+        let retry = retry_trigger(&job_to_run, &trigger, &build.id(), outcome.status);
+        build.retried = retry.is_some();
+        if let Err(write_err) = build.finish(outcome.status, outcome.step_results) {
+            error!(
+                "Failed to record the result of build {}: {}",
+                build.id(),
+                write_err
+            );
+        }
+        // what stopped the build must not stop its post-build scripts too
+        for _ in control_receiver.try_iter() {}
+        // the slot of the build is freed before the post-build scripts run, but a slow
+        // shutdown still waits for them
+        let _ = finished_notifier.send(FinishedBuild {
+            build_id: build.id(),
+            retry,
+        });
+        post_build::run_post_build_scripts(
+            &job_to_run,
+            &trigger,
+            &build,
+            &build_root,
+            &control_receiver,
+        );
+        let _ = post_build_notifier.send(build.id());
+    });
+    Some((build_id, control_sender))
+}
+
+/// The trigger of the next attempt at a build that failed because of its agent, along with
+/// how long to wait before queueing it, if the job has retries left. The delay doubles with
+/// every attempt.
+fn retry_trigger(
+    job_to_run: &Job,
+    trigger: &JobTrigger,
+    build_id: &str,
+    status: BuildStatus,
+) -> Option<(JobTrigger, Duration)> {
+    if status != BuildStatus::AgentFailure || trigger.attempt > job_to_run.settings.retries {
+        return None;
+    }
+    let delay = job_to_run
+        .settings
+        .retry_delay
+        .saturating_mul(2u32.saturating_pow(trigger.attempt - 1));
+    info!(
+        "Retrying build {} in {}s (attempt {} of {})",
+        build_id,
+        delay.as_secs(),
+        trigger.attempt + 1,
+        job_to_run.settings.retries + 1
+    );
+    Some((trigger.retry(build_id), delay))
+}
+
+fn run_job(
+    job_to_run: &Job,
+    trigger: &JobTrigger,
+    build: &BuildRecord,
+    pool_slot: Option<usize>,
+    interruptions: Receiver<BuildControl>,
+    timeout_notifier: Sender<BuildControl>,
+) -> BuildOutcome {
+    let mut agent_environment = trigger.environment();
+    agent_environment.extend(build_environment(build));
+    if let (Some(pool), Some(slot)) = (&job_to_run.pool, pool_slot) {
+        agent_environment.insert(String::from("FORMICA_POOL"), pool.name.clone());
+        agent_environment.insert(String::from("FORMICA_POOL_SLOT"), slot.to_string());
+    }
+    let (mut worker, mut logs) = match start_worker(job_to_run, build, &agent_environment) {
+        Ok(started) => started,
+        Err(start_err) => {
+            error!(
+                "The agent of build {} could not be started: {}",
+                build.id(),
+                start_err
+            );
+            return BuildOutcome {
+                status: BuildStatus::AgentFailure,
+                step_results: Vec::new(),
+                infrastructure_errors: vec![start_err],
+            };
+        }
+    };
+    let mut agent = AgentConnection::new(
+        worker.stdin.take().unwrap(),
+        worker.stdout.take().unwrap(),
+        &job_to_run.name,
+        interruptions.clone(),
+    );
+    let watchdog = launch_watchdog(&job_to_run.settings, build.id(), timeout_notifier);
+    let mut infrastructure_errors = Vec::new();
+
+    let build_result = agent
+        .handshake()
+        .and_then(|_| {
+            for (name, value) in trigger.environment() {
+                agent.set(&name, &value)?;
+            }
+            Ok(())
+        })
+        .map(|_| {
+            let step_results = run_steps(job_to_run, &mut agent, &mut logs, &watchdog);
+            if agent.interruption().is_none() {
+                upload_artifacts(job_to_run, &mut agent, &build.artifacts());
+            }
+            step_results
+        });
+    let (status, step_results) = match build_result {
+        Ok(step_results) => {
+            let failed_step = step_results
+                .iter()
+                .find(|step| step.status == StepStatus::AgentFailure);
+            if let Some(failed_step) = failed_step {
+                infrastructure_errors
+                    .push(format!("the agent could not run step {}", failed_step.name));
+            }
+            (BuildStatus::from_steps(&step_results), step_results)
+        }
+        Err(agent_err) => {
+            if agent.interruption().is_none() {
+                error!(
+                    "The agent of build {} could not be set up: {}",
+                    build.id(),
+                    agent_err
+                );
+                infrastructure_errors.push(format!("the agent could not be set up: {}", agent_err));
+            }
+            (BuildStatus::AgentFailure, Vec::new())
+        }
+    };
+
+    let interruption = match agent.interruption() {
+        Some(interruption) => Some(interruption),
+        // the agent no longer follows the protocol, so it is not asked to exit
+        None if status == BuildStatus::AgentFailure => None,
+        None => {
+            if let Err(exit_err) = agent.exit() {
+                debug!(
+                    "Could not ask the agent of {} to exit: {}",
+                    job_to_run.name, exit_err
+                );
+            }
+            // a worker that hangs instead of exiting still runs into the timeouts
+            wait_for_worker(&mut worker, &interruptions).unwrap_or_else(|wait_err| {
+                warn!(
+                    "Failed to wait for the worker of build {}: {}",
+                    build.id(),
+                    wait_err
+                );
+                None
+            })
+        }
+    };
+    drop(watchdog);
+    let (status, cleanup) = match interruption {
+        Some(interruption) => {
+            let cleanup = stop_worker(
+                &mut worker,
+                interruption,
+                job_to_run,
+                build,
+                &agent_environment,
+                &interruptions,
+            );
+            let status = match interruption {
+                BuildControl::Cancel => BuildStatus::Cancelled,
+                BuildControl::TimeOut => BuildStatus::TimedOut,
+                BuildControl::Abort | BuildControl::Kill => BuildStatus::Aborted,
+            };
+            (status, cleanup)
+        }
+        // the agent is cleaned up as for an aborted build, so that a retry starts afresh
+        None if status == BuildStatus::AgentFailure => {
+            let cleanup = stop_worker(
+                &mut worker,
+                BuildControl::Abort,
+                job_to_run,
+                build,
+                &agent_environment,
+                &interruptions,
+            );
+            (status, cleanup)
+        }
+        None => (status, Ok(())),
+    };
+    if let Err(cleanup_err) = cleanup {
+        infrastructure_errors.push(cleanup_err);
+    }
+    script::release_process_group(&worker);
+    BuildOutcome {
+        status,
+        step_results,
+        infrastructure_errors,
+    }
+}
+
+/// Spawns the worker of the build with the `agent_init` script of the job, or of its pool,
+/// and copies its standard error to the build logs.
+fn start_worker(
+    job_to_run: &Job,
+    build: &BuildRecord,
+    environment: &BTreeMap<String, String>,
+) -> Result<(Child, BuildLogs), String> {
+    let agent_init_script = match script::find_script(job_to_run.agent_folder(), AGENT_INIT) {
+        Ok(agent_init_script) => agent_init_script,
+        Err(script_err) => {
+            return Err(match script_err.kind {
+                NoScriptFound => format!("no {} script was found", AGENT_INIT),
+                TooManyScriptsFound(_) => {
+                    format!("more than one {} script was found", AGENT_INIT)
+                }
+            })
+        }
+    };
+    let logs = build
+        .open_logs()
+        .map_err(|open_err| format!("could not open the build logs: {}", open_err))?;
+    let mut stderr_log = logs
+        .stderr_handle()
+        .map_err(|open_err| format!("could not open the build logs: {}", open_err))?;
+    let mut worker =
+        script::spawn_worker_script(job_to_run.agent_folder(), &agent_init_script, environment)
+            .map_err(|spawn_err| format!("could not spawn {}: {}", AGENT_INIT, spawn_err))?;
+    let worker_errors = forward_lines(worker.stderr.take().unwrap());
+    thread::spawn(move || {
+        for line in worker_errors {
+            if let Err(write_err) = writeln!(stderr_log, "{}", line) {
+                warn!("Failed to write to the build log: {}", write_err);
+            }
+        }
+    });
+    Ok((worker, logs))
+}
+
+/// Sends `BuildControl::TimeOut` to the build once it runs past the timeout of its job, or
+/// one of its steps runs past the step timeout. The steps send `true` through the returned
+/// channel when they start and `false` when they end, and dropping it stops the watchdog.
+fn launch_watchdog(
+    job_settings: &JobSettings,
+    build_id: String,
+    timeout_notifier: Sender<BuildControl>,
+) -> Sender<bool> {
+    let (step_notifier, step_listener) = unbounded();
+    if job_settings.timeout.is_none() && job_settings.step_timeout.is_none() {
+        return step_notifier;
+    }
+    let build_deadline = job_settings.timeout.map(after).unwrap_or_else(never);
+    let step_timeout = job_settings.step_timeout;
+    thread::spawn(move || {
+        let mut step_deadline = never();
+        loop {
+            select! {
+                recv(step_listener) -> step_running => match step_running {
+                    Ok(true) => step_deadline = step_timeout.map(after).unwrap_or_else(never),
+                    Ok(false) => step_deadline = never(),
+                    Err(_) => return,
+                },
+                recv(build_deadline) -> _ => {
+                    warn!("Build {} timed out", build_id);
+                    break;
+                }
+                recv(step_deadline) -> _ => {
+                    warn!("A step of build {} timed out", build_id);
+                    break;
+                }
+            }
+        }
+        let _ = timeout_notifier.send(BuildControl::TimeOut);
+    });
+    step_notifier
+}
+
+/// Stops the worker of an interrupted build along with everything it started, then cleans
+/// up its agent unless the build was killed. The worker of a timed out build is first asked
+/// to stop with SIGTERM, and only killed after the cleanup if it has not exited by then.
+/// Returns why the cleanup failed, if it did.
+fn stop_worker(
+    worker: &mut Child,
+    interruption: BuildControl,
+    job_to_run: &Job,
+    build: &BuildRecord,
+    environment: &BTreeMap<String, String>,
+    interruptions: &Receiver<BuildControl>,
+) -> Result<(), String> {
+    info!("Terminating the worker of build {}", build.id());
+    // a cancelled or timed out build gets a chance to stop cleanly, but a shutdown does not wait
+    let stopped = if matches!(interruption, BuildControl::Cancel | BuildControl::TimeOut) {
+        script::terminate_process_tree(worker)
+            .and_then(|_| wait_for_exit(worker, TERMINATION_GRACE_PERIOD))
+    } else {
+        script::kill_process_tree(worker)
+            .and_then(|_| wait_for_exit(worker, TERMINATION_GRACE_PERIOD))
+    };
+    if let Err(stop_err) = stopped {
+        warn!(
+            "Failed to terminate the worker of build {}: {}",
+            build.id(),
+            stop_err
+        );
+    }
+    let cleanup = if interruption != BuildControl::Kill {
+        run_cleanup(job_to_run, build, environment, interruptions)
+    } else {
+        Ok(())
+    };
+    if let Ok(false) = script::has_exited(worker) {
+        warn!(
+            "The worker of build {} is still running, killing it",
+            build.id()
+        );
+    }
+    // the processes started by the worker may have outlived it; the worker is only reaped
+    // afterwards, so that the id of its process group cannot have been reused
+    if let Err(kill_err) = script::kill_process_tree(worker) {
+        warn!(
+            "Failed to kill the processes of build {}: {}",
+            build.id(),
+            kill_err
+        );
+    }
+    let _ = worker.wait();
+    cleanup
+}
+
+/// Waits for the process to exit, for at most `timeout`, without reaping it.
+fn wait_for_exit(process: &mut Child, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    while !script::has_exited(process)? && Instant::now() < deadline {
+        thread::sleep(PROCESS_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Waits for the worker to exit, returning the build control message that arrives
+/// meanwhile instead, if any.
+fn wait_for_worker(
+    worker: &mut Child,
+    interruptions: &Receiver<BuildControl>,
+) -> io::Result<Option<BuildControl>> {
+    let mut interruptions = interruptions.clone();
+    while worker.try_wait()?.is_none() {
+        select! {
+            recv(interruptions) -> interruption => match interruption {
+                Ok(interruption) => return Ok(Some(interruption)),
+                // nobody can interrupt the worker anymore, so just wait for it
+                Err(_) => interruptions = never(),
+            },
+            default(PROCESS_POLL_INTERVAL) => (),
+        }
+    }
+    Ok(None)
+}
+
+/// Runs the `agent_cleanup` script of the job, or of its pool (if any), so that it can clean
+/// up the agent after its worker was terminated. The output of the script goes to the build logs.
+/// Returns why the cleanup failed, if it did.
+fn run_cleanup(
+    job_to_run: &Job,
+    build: &BuildRecord,
+    environment: &BTreeMap<String, String>,
+    interruptions: &Receiver<BuildControl>,
+) -> Result<(), String> {
+    let cleanup_script =
+        match script::find_optional_script(job_to_run.agent_folder(), AGENT_CLEANUP) {
+            Ok(Some(cleanup_script)) => cleanup_script,
+            Ok(None) => return Ok(()),
+            Err(_) => {
+                warn!(
+                    "More than one {} script found for {}, not cleaning up!",
+                    AGENT_CLEANUP, job_to_run.name
+                );
+                return Err(format!("more than one {} script was found", AGENT_CLEANUP));
+            }
+        };
+    info!("Cleaning up the agent of build {}", build.id());
+    let cleanup = build.open_logs().and_then(|logs| {
+        let mut cleanup_process = script::spawn_logged_script(
+            job_to_run.agent_folder(),
+            &cleanup_script,
+            environment,
+            logs,
+        )?;
+        let exit_status = wait_for_process(&mut cleanup_process, interruptions);
+        script::release_process_group(&cleanup_process);
+        exit_status
+    });
+    let cleanup_err = match cleanup {
+        Ok(exit_status) if exit_status.success() => return Ok(()),
+        Ok(exit_status) => format!("{} failed with {}", AGENT_CLEANUP, exit_status),
+        Err(cleanup_err) => format!("{} could not be run: {}", AGENT_CLEANUP, cleanup_err),
+    };
+    warn!(
+        "The cleanup of build {} failed: {}",
+        build.id(),
+        cleanup_err
+    );
+    Err(cleanup_err)
+}
+
+/// Waits for the process to exit, killing it (along with everything it started) if a build
+/// control message arrives meanwhile.
+fn wait_for_process(
+    process: &mut Child,
+    interruptions: &Receiver<BuildControl>,
+) -> io::Result<ExitStatus> {
+    loop {
+        if let Some(exit_status) = process.try_wait()? {
+            return Ok(exit_status);
+        }
+        select! {
+            recv(interruptions) -> interruption => {
+                if interruption.is_ok() {
+                    script::kill_process_tree(process)?;
+                    return process.wait();
+                }
+                // nobody can interrupt the process anymore, so just wait for it
+                return process.wait();
+            }
+            default(PROCESS_POLL_INTERVAL) => (),
+        }
+    }
+}
+
+/// The variables describing the build, exported to the `agent_init` script.
+fn build_environment(build: &BuildRecord) -> BTreeMap<String, String> {
+    let mut environment = BTreeMap::new();
+    environment.insert(String::from("FORMICA_JOB_NAME"), build.job_name.clone());
+    environment.insert(
+        String::from("FORMICA_BUILD_NUMBER"),
+        build.number.to_string(),
+    );
+    environment.insert(
+        String::from("FORMICA_BUILD_DIR"),
+        absolute_path(build.dir.clone()),
+    );
+    environment.insert(
+        String::from("FORMICA_WORKSPACE"),
+        absolute_path(build.workspace()),
+    );
+    environment
+}
+
+/// The absolute form of the path, for the scripts that do not run in the current folder.
+fn absolute_path(path: PathBuf) -> String {
+    path.canonicalize()
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+fn run_steps(
+    job_to_run: &Job,
+    agent: &mut AgentConnection<impl Write>,
+    logs: &mut BuildLogs,
+    watchdog: &Sender<bool>,
+) -> Vec<StepResult> {
+    let mut step_results = Vec::new();
+    for step in job_to_run.steps.iter() {
+        let step_name = step.file_name().unwrap().to_string_lossy().to_string();
+        info!("[{}] Running step {}", job_to_run.name, step_name);
+        let step_start = Instant::now();
+        let _ = watchdog.send(true);
+        let status = match fs::read_to_string(job_to_run.root_folder.join(step)) {
+            Ok(step_script) => match agent.run(&step_name, &step_script, |stream, line| {
+                logs.write_line(stream, line)
+            }) {
+                Ok(0) => StepStatus::Success,
+                Ok(exit_code) => StepStatus::Failed(exit_code),
+                Err(_) if agent.interruption() == Some(BuildControl::TimeOut) => {
+                    StepStatus::TimedOut
+                }
+                Err(_) if agent.interruption().is_some() => StepStatus::Interrupted,
+                Err(run_err) => {
+                    error!("Could not run step {} on the agent: {}", step_name, run_err);
+                    StepStatus::AgentFailure
+                }
+            },
+            Err(read_err) => {
+                error!("Could not read step {}: {}", step.display(), read_err);
+                StepStatus::AgentFailure
+            }
+        };
+        let _ = watchdog.send(false);
+        let step_result = StepResult {
+            name: step_name,
+            status,
+            duration: step_start.elapsed(),
+        };
+        info!(
+            "[{}] Step {} finished with status {:?} in {:.1}s",
+            job_to_run.name,
+            step_result.name,
+            step_result.status,
+            step_result.duration.as_secs_f64()
+        );
+        let failed = step_result.status != StepStatus::Success;
+        step_results.push(step_result);
+        if failed {
+            break;
+        }
+    }
+    step_results
+}
+
+/// Fetches the files listed in the `artifacts` file of the job (one path per line) from the agent.
+fn upload_artifacts(
+    job_to_run: &Job,
+    agent: &mut AgentConnection<impl Write>,
+    artifact_dir: &Path,
+) {
+    let artifact_list = match fs::read_to_string(job_to_run.root_folder.join(ARTIFACTS)) {
+        Ok(artifact_list) => artifact_list,
+        Err(_) => return,
+    };
+    for artifact in artifact_list.lines().map(str::trim) {
+        if artifact.is_empty() || artifact.starts_with('#') {
+            continue;
+        }
+        let artifact_path = Path::new(artifact);
+        if !artifact_path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            warn!(
+                "[{}] Skipping artifact {}: artifact paths must be relative, without '..'",
+                job_to_run.name, artifact
+            );
+            continue;
+        }
+        let stored = agent
+            .upload(artifact)
+            .map_err(|upload_err| upload_err.to_string())
+            .and_then(|contents| {
+                let destination = artifact_dir.join(artifact_path);
+                fs::create_dir_all(destination.parent().unwrap())
+                    .and_then(|_| fs::write(&destination, contents))
+                    .map_err(|write_err| write_err.to_string())
+            });
+        match stored {
+            Ok(()) => info!("[{}] Stored artifact {}", job_to_run.name, artifact),
+            Err(upload_err) => warn!(
+                "[{}] Could not upload artifact {}: {}",
+                job_to_run.name, artifact, upload_err
+            ),
+        }
+    }
+}
+
+/// Sends every line read from `stream` through the returned channel, from a separate thread.
+fn forward_lines(stream: impl Read + Send + 'static) -> Receiver<String> {
+    let (sender, receiver) = unbounded();
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            match line {
+                Ok(line) => {
+                    if sender.send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
 }
 
 fn config_fetch() -> Result<(), InitError> {
@@ -178,21 +1298,138 @@ fn is_agent_init_script(entry: &DirEntry) -> bool {
             .unwrap_or(false)
 }
 
+/// Lists the step scripts of the job folder, in the order in which they should run.
+fn find_steps(job_folder: &Path) -> Vec<PathBuf> {
+    let mut steps = match fs::read_dir(job_folder) {
+        Ok(files) => Vec::from_iter(
+            files
+                .filter_map(|file| file.ok())
+                .filter(|file| file.file_type().map(|ft| ft.is_file()).unwrap_or(false))
+                .filter(|file| {
+                    file.file_name()
+                        .to_str()
+                        .map(|name| name.starts_with(STEP_PREFIX))
+                        .unwrap_or(false)
+                })
+                .map(|file| PathBuf::from(file.file_name())),
+        ),
+        Err(list_err) => {
+            warn!(
+                "Failed to list the steps in {}: {}",
+                job_folder.display(),
+                list_err
+            );
+            Vec::new()
+        }
+    };
+    steps.sort();
+    steps
+}
+
+fn is_job_settings_file(entry: &DirEntry) -> bool {
+    entry.file_type().is_file() && entry.file_name() == JOB_SETTINGS_FILE
+}
+
+/// The pools folder holds `agent_init` scripts too, but not jobs.
+fn is_pools_dir(entry: &DirEntry) -> bool {
+    entry.depth() == 1 && entry.file_name() == POOLS_DIR
+}
+
+fn is_hidden(entry: &DirEntry) -> bool {
+    entry.depth() > 0
+        && entry
+            .file_name()
+            .to_str()
+            .map(|name| name.starts_with('.'))
+            .unwrap_or(false)
+}
+
+/// Job names given from outside (e.g. in a URL) must not lead outside of the build folder.
+fn is_safe_job_name(job_name: &str) -> bool {
+    !job_name.is_empty()
+        && Path::new(job_name)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Builds a name out of the path of `path` relative to `root`, with `/` as separator
+/// on every platform (e.g. `backend/unit_tests`).
+fn relative_name(root: &Path, path: &Path) -> Option<String> {
+    let components: Option<Vec<&str>> = path
+        .strip_prefix(root)
+        .ok()?
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect();
+    let components = components?;
+    if components.is_empty() {
+        return None;
+    }
+    Some(components.join("/"))
+}
+
+fn find_poll_script(job_folder: &PathBuf, job_name: &str) -> Option<String> {
+    match script::find_optional_script(job_folder, poll::POLL) {
+        Ok(poll_script) => poll_script,
+        Err(_) => {
+            warn!(
+                "More than one {} script found for {}, not polling it!",
+                poll::POLL,
+                job_name
+            );
+            None
+        }
+    }
+}
+
 fn find_jobs() -> Result<Vec<Job>, JobRunnerError> {
-    let jobs = Vec::from_iter(
-        WalkDir::new(CONFIG)
+    let config_dir = Path::new(CONFIG);
+    let pools: Vec<Arc<Pool>> = pool::find_pools(config_dir)
+        .into_iter()
+        .map(Arc::new)
+        .collect();
+    // a job folder holds an agent_init script, or a job.toml asking for a pool instead
+    let mut job_folders = Vec::from_iter(
+        WalkDir::new(config_dir)
             .follow_links(true)
             .into_iter()
+            .filter_entry(|entry| !is_hidden(entry) && !is_pools_dir(entry))
             .filter_map(|f| f.ok())
-            .filter(|file| is_agent_init_script(file))
-            .map(|agent_init_script| {
-                let job_folder = agent_init_script.path().parent().unwrap().to_path_buf();
-                Job {
-                    name: String::from("a job"),
-                    root_folder: job_folder,
-                }
-            }),
+            .filter(|entry| is_agent_init_script(entry) || is_job_settings_file(entry))
+            .map(|job_file| job_file.path().parent().unwrap().to_path_buf()),
     );
+    // a folder with both is found twice
+    job_folders.sort();
+    job_folders.dedup();
+    let mut jobs = Vec::from_iter(job_folders.into_iter().filter_map(|job_folder| {
+        let name = match relative_name(config_dir, &job_folder) {
+            Some(name) => name,
+            None => {
+                warn!(
+                    "Ignoring {}: jobs must be in a subfolder of {}",
+                    job_folder.display(),
+                    CONFIG
+                );
+                return None;
+            }
+        };
+        if Path::new(&name).starts_with(queue::CANCEL_DIR) {
+            warn!(
+                "Job {} cannot be triggered through trigger files, since the {} folder of the queue holds cancellation requests",
+                name,
+                queue::CANCEL_DIR
+            );
+        }
+        match load_job(name.clone(), job_folder, &pools) {
+            Ok(job) => Some(job),
+            Err(reason) => {
+                error!("Ignoring job {}: {}", name, reason);
+                None
+            }
+        }
+    }));
+    jobs.sort_by(|job, other_job| job.name.cmp(&other_job.name));
+    jobs.dedup_by(|job, other_job| job.name == other_job.name);
     if jobs.is_empty() {
         return Err(JobRunnerError {
             kind: JobRunnerErrorKind::NoJobsFound,
@@ -202,64 +1439,425 @@ fn find_jobs() -> Result<Vec<Job>, JobRunnerError> {
     Ok(jobs)
 }
 
-fn build_job_queue_channel() -> Result<Receiver<String>, InitError> {
+/// Reads the job of a job folder, returning why it is invalid if it is.
+fn load_job(name: String, job_folder: PathBuf, pools: &[Arc<Pool>]) -> Result<Job, String> {
+    let settings =
+        JobSettings::load(&job_folder).map_err(|settings_err| settings_err.to_string())?;
+    let has_agent_init = !matches!(
+        script::find_optional_script(&job_folder, AGENT_INIT),
+        Ok(None)
+    );
+    let pool = match &settings.pool {
+        Some(_) if has_agent_init => {
+            return Err(format!(
+                "it has both an {} script and a pool, pick one",
+                AGENT_INIT
+            ))
+        }
+        Some(pool_name) => Some(
+            pools
+                .iter()
+                .find(|pool| pool.name == *pool_name)
+                .cloned()
+                .ok_or_else(|| format!("unknown agent pool {}", pool_name))?,
+        ),
+        None if has_agent_init => None,
+        None => {
+            return Err(format!(
+                "it has neither an {} script nor a pool",
+                AGENT_INIT
+            ))
+        }
+    };
+    let steps = find_steps(&job_folder);
+    let invalid_step = steps
+        .iter()
+        .map(|step| step.to_string_lossy())
+        .find(|step_name| !protocol::is_valid_step_name(step_name));
+    if let Some(step_name) = invalid_step {
+        return Err(format!(
+            "the name of step '{}' contains whitespace, which the agent protocol cannot carry",
+            step_name
+        ));
+    }
+    Ok(Job {
+        steps,
+        schedule: schedule::read_schedule(&job_folder, &name),
+        poll_script: find_poll_script(&job_folder, &name),
+        settings,
+        pool,
+        name,
+        root_folder: job_folder,
+    })
+}
+
+/// Gathers the triggers of the queue directory in a channel, and starts the job schedules
+/// and the `poll` scripts. These queue their builds through `request_notifier` instead, so
+/// that they only record the builds the orchestrator accepted. The cancellations requested
+/// through the queue directory go to `request_notifier` too.
+fn build_job_trigger_channel(
+    settings: SharedSettings,
+    jobs: SharedJobs,
+    request_notifier: Sender<OrchestratorRequest>,
+) -> Receiver<JobTrigger> {
     let (sender, receiver) = unbounded();
-    let job_queue_poll_freq = Duration::from_secs(1);
+    queue::launch_job_queue_poller(
+        settings.clone(),
+        jobs.clone(),
+        sender,
+        request_notifier.clone(),
+    );
+    schedule::launch_scheduler(settings.clone(), jobs.clone(), request_notifier.clone());
+    poll::launch_revision_poller(settings, jobs, request_notifier);
+    receiver
+}
 
-    // TODO: add mechanism to add files
-    fs::create_dir_all(QUEUE_DIR).expect("Failed to create queue watch folder!");
+/// Asks the orchestrator to queue a build, for the threads that record what they triggered
+/// once it is accepted. Returns whether it was, or `None` if the orchestrator is gone.
+fn request_build(
+    request_notifier: &Sender<OrchestratorRequest>,
+    trigger: JobTrigger,
+) -> Option<bool> {
+    let (reply_notifier, reply_listener) = bounded(1);
+    request_notifier
+        .send(OrchestratorRequest::QueueBuild {
+            trigger,
+            reply: reply_notifier,
+        })
+        .ok()?;
+    // the orchestrator answers right away, or drops the request when it stops
+    reply_listener.recv().ok()
+}
 
-    thread::spawn(move || loop {
-        thread::sleep(job_queue_poll_freq);
-        info!("Queueing a job!");
-        let _ = sender.send(String::from("integration_test"));
-    });
-    Ok(receiver)
+fn reload_settings(settings: &SharedSettings) -> Result<(), String> {
+    match Settings::load(Path::new(CONFIG)) {
+        Ok(new_settings) => {
+            new_settings.apply_log_level();
+            let mut current_settings = settings.write().unwrap();
+            if *current_settings != new_settings {
+                info!("Applying the updated {}", settings::SETTINGS_FILE);
+                *current_settings = new_settings;
+            }
+            Ok(())
+        }
+        Err(settings_err) => {
+            error!("Keeping the previous settings: {}", settings_err);
+            Err(settings_err.to_string())
+        }
+    }
+}
+
+/// Scans the configuration directory for jobs again, and swaps them in at once.
+fn reload_jobs(jobs: &SharedJobs) -> Result<(), String> {
+    let new_jobs = match find_jobs() {
+        Ok(new_jobs) => new_jobs,
+        Err(find_err) => {
+            match find_err.kind {
+                JobRunnerErrorKind::NoJobsFound => {
+                    error!("No jobs were found after the configuration update, keeping the previous jobs!");
+                    return Err("no jobs were found".to_string());
+                }
+            }
+        }
+    };
+    let mut current_jobs = jobs.write().unwrap();
+    for job in new_jobs.iter() {
+        if !current_jobs
+            .iter()
+            .any(|current_job| current_job.name == job.name)
+        {
+            info!("Found new job {}", job.name);
+        }
+    }
+    for current_job in current_jobs.iter() {
+        if !new_jobs.iter().any(|job| job.name == current_job.name) {
+            info!("Job {} has been removed", current_job.name);
+        }
+    }
+    *current_jobs = new_jobs.into_iter().map(Arc::new).collect();
+    Ok(())
 }
-fn launch_job_queue_poller() {
-    // TODO: create queue folder if missing
-    // poll queue folder for files
+
+/// Kills the workers and cleanup scripts still running, along with everything they started,
+/// when Formica exits without waiting for them.
+pub fn kill_all_scripts() {
+    script::kill_all_process_groups();
 }
 
-fn launch_background_updater() {
-    // TODO : configuration parse
-    let job_update_delay = Duration::from_secs(5 * 60);
+fn find_job(jobs: &SharedJobs, job_name: &str) -> Option<Arc<Job>> {
+    jobs.read()
+        .unwrap()
+        .iter()
+        .find(|job| job.name == job_name)
+        .cloned()
+}
 
+/// Runs the `update` script, then reloads the settings and the jobs if it succeeded.
+/// Returns why the configuration could not be updated, if it could not.
+fn run_config_update(settings: &SharedSettings, jobs: &SharedJobs) -> Result<(), String> {
+    match update_config() {
+        Ok(Ok(update_output)) if update_output.status.success() => {
+            debug!("The configuration was updated");
+            let settings_reloaded = reload_settings(settings);
+            let jobs_reloaded = reload_jobs(jobs);
+            settings_reloaded.and(jobs_reloaded)
+        }
+        Ok(Ok(update_output)) => {
+            error!(
+                "The update script failed with {}, keeping the current configuration!\nOutput:\n{}\nError output:\n{}",
+                update_output.status,
+                String::from_utf8_lossy(&update_output.stdout),
+                String::from_utf8_lossy(&update_output.stderr)
+            );
+            Err(format!(
+                "the update script failed with {}",
+                update_output.status
+            ))
+        }
+        Ok(Err(execution_err)) => {
+            error!("The update script could not be run: {}", execution_err);
+            Err(format!(
+                "the update script could not be run: {}",
+                execution_err
+            ))
+        }
+        Err(update_err) => match update_err.kind {
+            NoScriptFound => {
+                warn!("Update script has disappeared!");
+                Err("the update script has disappeared".to_string())
+            }
+            TooManyScriptsFound(_) => {
+                warn!("Unexpectedly, more than one update script found!");
+                Err("more than one update script was found".to_string())
+            }
+        },
+    }
+}
+
+/// Runs the configuration update periodically, or right away when asked to.
+fn launch_background_updater(
+    settings: SharedSettings,
+    jobs: SharedJobs,
+    updater_status: SharedUpdaterStatus,
+    mut update_requests: Receiver<()>,
+) {
     thread::spawn(move || {
-        let last_execution_time = Instant::now();
+        let mut last_execution_time = Instant::now();
         loop {
-            thread::sleep(Duration::from_secs(1));
-            if Instant::now().duration_since(last_execution_time) > job_update_delay {
-                match update_config() {
-                    Ok(_) => (),
-                    Err(update_err) => match update_err.kind {
-                        NoScriptFound => warn!("Update script has disappeared!"),
-                        TooManyScriptsFound(_) => {
-                            warn!("Unexpectedly, more than one update script found!")
-                        }
-                    },
+            let update_requested = select! {
+                recv(update_requests) -> request => match request {
+                    Ok(()) => true,
+                    Err(_) => {
+                        update_requests = never();
+                        false
+                    }
+                },
+                default(Duration::from_secs(1)) => false,
+            };
+            let job_update_delay = settings.read().unwrap().update_interval;
+            if update_requested
+                || Instant::now().duration_since(last_execution_time) >= job_update_delay
+            {
+                last_execution_time = Instant::now();
+                updater_status.write().unwrap().running = true;
+                let update_result = run_config_update(&settings, &jobs);
+                let mut status = updater_status.write().unwrap();
+                status.running = false;
+                status.last_run = Some(SystemTime::now());
+                match update_result {
+                    Ok(()) => {
+                        status.last_success = status.last_run;
+                        status.last_error = None;
+                    }
+                    Err(update_err) => status.last_error = Some(update_err),
                 }
             }
         }
     });
 }
 
+/// The jobs found in the configuration directory, replaced as a whole when the
+/// configuration is updated. Builds hold on to the definition of the job they started with.
+pub type SharedJobs = Arc<RwLock<Vec<Arc<Job>>>>;
+
 pub struct Job {
+    /// Path of the job folder relative to the configuration directory, e.g. `backend/unit_tests`.
     name: String,
     root_folder: PathBuf,
-    //steps: Vec<PathBuf>
+    /// File names of the step scripts, relative to the root folder, in running order.
+    steps: Vec<PathBuf>,
+    /// The entries of the `schedule` file of the job, if any.
+    schedule: Vec<ScheduleEntry>,
+    /// File name of the `poll` script of the job, if it has one.
+    poll_script: Option<String>,
+    /// The settings of the `job.toml` file of the job, if it has one.
+    settings: JobSettings,
+    /// The agent pool the job runs on, instead of its own `agent_init` script.
+    pool: Option<Arc<Pool>>,
+}
+
+impl Job {
+    /// The folder holding the `agent_init` and `agent_cleanup` scripts of the job: the
+    /// folder of its pool, if it has one.
+    fn agent_folder(&self) -> &PathBuf {
+        match &self.pool {
+            Some(pool) => &pool.root_folder,
+            None => &self.root_folder,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Job {
+    /// A job without steps, for the tests that only look at its name and settings.
+    fn for_tests(name: &str, settings: JobSettings) -> Job {
+        Job {
+            name: name.to_string(),
+            root_folder: PathBuf::from(name),
+            steps: Vec::new(),
+            schedule: Vec::new(),
+            poll_script: None,
+            settings,
+            pool: None,
+        }
+    }
+}
+
+/// A build started by the orchestrator, until it finishes.
+struct RunningBuild {
+    job_name: String,
+    /// The name of the pool of the job and the slot of the pool the build uses, if any.
+    pool_slot: Option<(String, usize)>,
+    control: Sender<BuildControl>,
+}
+
+/// Sent by the thread of a build to the orchestrator once the build has finished.
+struct FinishedBuild {
+    build_id: String,
+    /// The trigger of the next attempt at the build, and how long to wait before queueing it,
+    /// if the build failed because of its agent and the job retries such builds.
+    retry: Option<(JobTrigger, Duration)>,
+}
+
+/// How a build ended, as reported by `run_job`.
+struct BuildOutcome {
+    status: BuildStatus,
+    step_results: Vec<StepResult>,
+    /// What went wrong with the agent rather than with the steps, e.g. the `agent_init`
+    /// script could not be spawned, or the `agent_cleanup` script failed.
+    infrastructure_errors: Vec<String>,
+}
+
+/// What the background updater did last, shared with the dashboard.
+pub type SharedUpdaterStatus = Arc<RwLock<UpdaterStatus>>;
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub struct UpdaterStatus {
+    /// Whether the `update` script is running right now.
+    pub running: bool,
+    pub last_run: Option<SystemTime>,
+    /// When the configuration was last updated and reloaded without errors.
+    pub last_success: Option<SystemTime>,
+    /// Why the last update failed, if it did.
+    pub last_error: Option<String>,
+}
+
+/// Messages sent by the orchestrator to a running build.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuildControl {
+    /// Terminate the worker, then clean up the agent with the `agent_cleanup` script.
+    Abort,
+    /// Terminate the worker without cleaning up the agent.
+    Kill,
+    /// Like `Abort`, but the build was stopped on request rather than by a shutdown. The
+    /// worker is asked to stop with SIGTERM first, and only killed after the cleanup.
+    Cancel,
+    /// Like `Cancel`, but the build ran past one of its timeouts.
+    TimeOut,
+}
+
+/// Requests made to the orchestrator from outside (e.g. through the HTTP API).
+pub enum OrchestratorRequest {
+    /// Queues a build, replying whether it was accepted: triggers are refused during a
+    /// shutdown, since nothing would keep them until the next start.
+    QueueBuild {
+        trigger: JobTrigger,
+        reply: Sender<bool>,
+    },
+    /// Cancels a running build, replying whether the build was running.
+    CancelBuild {
+        build_id: String,
+        reply: Sender<bool>,
+    },
+    /// Removes the builds of the job waiting for a free slot, replying how many there were.
+    CancelWaitingBuilds {
+        job_name: String,
+        reply: Sender<usize>,
+    },
+    /// Replies with the running builds and the builds waiting for a free slot.
+    ListBuilds { reply: Sender<OrchestratorStatus> },
+}
+
+pub struct OrchestratorStatus {
+    /// The ids of the running builds, e.g. `backend/unit_tests/12`.
+    pub running_builds: Vec<String>,
+    /// The job names and trigger sources of the builds waiting for a free slot, in order.
+    pub pending_builds: Vec<(String, String)>,
+}
+
+pub struct StepResult {
+    pub name: String,
+    pub status: StepStatus,
+    pub duration: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StepStatus {
+    Success,
+    Failed(i32),
+    /// The worker could not run the step, or did not report its result.
+    AgentFailure,
+    /// The build was stopped while the step was running.
+    Interrupted,
+    /// The step, or the whole build, ran past its timeout.
+    TimedOut,
 }
 
+impl fmt::Display for StepStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StepStatus::Success => write!(f, "SUCCESS"),
+            StepStatus::Failed(exit_code) => write!(f, "FAILURE({})", exit_code),
+            StepStatus::AgentFailure => write!(f, "AGENT_FAILURE"),
+            StepStatus::Interrupted => write!(f, "ABORTED"),
+            StepStatus::TimedOut => write!(f, "TIMED_OUT"),
+        }
+    }
+}
+
+/// The channels through which the orchestrator is controlled (e.g. on Ctrl + C or signals).
 pub struct ShutdownNotifiers {
     pub slow_shutdown: Sender<()>,
     pub immediate_shutdown: Sender<()>,
     pub force_termination: Sender<()>,
+    /// Starts a slow shutdown that turns into an immediate one after the shutdown timeout,
+    /// or right away if it is requested again.
+    pub graceful_shutdown: Sender<()>,
+    /// Receives a message once a shutdown has been requested and no builds are running anymore.
+    pub shutdown_complete: Receiver<()>,
+    /// Runs the `update` script and reloads the configuration now.
+    pub config_update: Sender<()>,
+    /// Logs the running builds and the builds waiting for a free slot.
+    pub state_dump: Sender<()>,
 }
 
 pub struct ShutdownListeners {
     pub slow_shutdown: Receiver<()>,
     pub immediate_shutdown: Receiver<()>,
     pub force_termination: Receiver<()>,
+    pub graceful_shutdown: Receiver<()>,
+    pub state_dump: Receiver<()>,
 }
 
 #[derive(Debug)]
@@ -285,4 +1883,320 @@ pub enum InitErrorKind {
     NoUpdateScriptInsideConfig,
     TooManyUpdateScriptsFound(Vec<String>),
     UpdateScriptExecutionError(Output),
+    NoJobsFound,
+    InvalidSettings(SettingsError),
+    /// The HTTP API could not listen on the given address.
+    #[cfg(feature = "http")]
+    HttpServerError(String, String),
+    /// The control socket could not be created.
+    ControlSocketError(PathBuf, io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(name: &str, settings: JobSettings) -> Arc<Job> {
+        Arc::new(Job::for_tests(name, settings))
+    }
+
+    fn trigger(job_name: &str, source: &str, parameters: &[(&str, &str)]) -> JobTrigger {
+        let mut trigger = JobTrigger::new(job_name, source.to_string(), None);
+        for (name, value) in parameters {
+            trigger
+                .parameters
+                .insert(name.to_string(), value.to_string());
+        }
+        trigger
+    }
+
+    /// Running builds, as build ids along with the pool slot they use.
+    fn running(builds: &[(&str, Option<(&str, usize)>)]) -> HashMap<String, RunningBuild> {
+        builds
+            .iter()
+            .map(|(build_id, pool_slot)| {
+                let build = RunningBuild {
+                    job_name: build_id.rsplit_once('/').unwrap().0.to_string(),
+                    pool_slot: pool_slot.map(|(pool_name, slot)| (pool_name.to_string(), slot)),
+                    control: unbounded().0,
+                };
+                (build_id.to_string(), build)
+            })
+            .collect()
+    }
+
+    fn waiting(pending_builds: &VecDeque<(Arc<Job>, JobTrigger)>) -> Vec<(&str, &str)> {
+        pending_builds
+            .iter()
+            .map(|(job, trigger)| (job.name.as_str(), trigger.source.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn builds_wait_by_priority() {
+        let settings = SharedSettings::default();
+        let running_builds = running(&[]);
+        let priority = |priority| JobSettings {
+            priority,
+            ..JobSettings::default()
+        };
+        let nightly = job("nightly", priority(-1));
+        let docs = job("docs", priority(0));
+        let release = job("release", priority(5));
+
+        let mut pending_builds = VecDeque::new();
+        for (job, source) in [
+            (&docs, "cli"),
+            (&nightly, "schedule"),
+            (&release, "cli"),
+            (&docs, "poll"),
+            (&release, "poll"),
+            (&nightly, "cli"),
+        ] {
+            let trigger = trigger(&job.name, source, &[]);
+            queue_build(
+                job.clone(),
+                trigger,
+                &mut pending_builds,
+                &settings,
+                &running_builds,
+            );
+        }
+        assert_eq!(
+            waiting(&pending_builds),
+            vec![
+                ("release", "cli"),
+                ("release", "poll"),
+                ("docs", "cli"),
+                ("docs", "poll"),
+                ("nightly", "schedule"),
+                ("nightly", "cli"),
+            ]
+        );
+    }
+
+    #[test]
+    fn triggers_coalesce() {
+        let settings = SharedSettings::default();
+        let running_builds = running(&[]);
+        let coalesce = JobSettings {
+            coalesce: true,
+            ..JobSettings::default()
+        };
+        let backend = job("backend", coalesce.clone());
+        let frontend = job("frontend", coalesce);
+        let docs = job("docs", JobSettings::default());
+
+        let mut pending_builds = VecDeque::new();
+        for (job, source, branch) in [
+            (&backend, "cli", "main"),
+            (&docs, "cli", "main"),
+            (&frontend, "cli", "main"),
+            (&backend, "poll", "release"),
+            (&docs, "poll", "release"),
+            (&backend, "schedule", "release"),
+        ] {
+            let trigger = trigger(&job.name, source, &[("BRANCH", branch)]);
+            queue_build(
+                job.clone(),
+                trigger,
+                &mut pending_builds,
+                &settings,
+                &running_builds,
+            );
+        }
+        // the latest trigger wins, parameters included, but the build keeps its place
+        assert_eq!(
+            waiting(&pending_builds),
+            vec![
+                ("backend", "schedule"),
+                ("docs", "cli"),
+                ("frontend", "cli"),
+                ("docs", "poll"),
+            ]
+        );
+        let branches: Vec<&str> = pending_builds
+            .iter()
+            .map(|(_, trigger)| trigger.parameters["BRANCH"].as_str())
+            .collect();
+        assert_eq!(branches, vec!["release", "main", "main", "release"]);
+    }
+
+    #[test]
+    fn running_builds_do_not_coalesce() {
+        let settings = SharedSettings::default();
+        let running_builds = running(&[("backend/1", None)]);
+        let backend = job(
+            "backend",
+            JobSettings {
+                coalesce: true,
+                ..JobSettings::default()
+            },
+        );
+
+        let mut pending_builds = VecDeque::new();
+        let trigger = trigger("backend", "poll", &[]);
+        queue_build(
+            backend,
+            trigger,
+            &mut pending_builds,
+            &settings,
+            &running_builds,
+        );
+        assert_eq!(waiting(&pending_builds), vec![("backend", "poll")]);
+    }
+
+    #[test]
+    fn max_concurrent_builds() {
+        let limited = job(
+            "backend",
+            JobSettings {
+                max_concurrent_builds: Some(2),
+                ..JobSettings::default()
+            },
+        );
+        let unlimited = job("docs", JobSettings::default());
+
+        let running_builds = running(&[("backend/1", None), ("docs/1", None), ("docs/2", None)]);
+        assert!(can_start(&limited, &running_builds));
+        assert!(can_start(&unlimited, &running_builds));
+
+        let running_builds = running(&[("backend/1", None), ("backend/2", None)]);
+        assert!(!can_start(&limited, &running_builds));
+        assert!(can_start(&unlimited, &running_builds));
+    }
+
+    fn pool(name: &str, capacity: usize) -> Arc<Pool> {
+        Arc::new(Pool {
+            name: name.to_string(),
+            root_folder: PathBuf::from(POOLS_DIR).join(name),
+            capacity,
+        })
+    }
+
+    fn job_on_pool(name: &str, pool: &Arc<Pool>) -> Arc<Job> {
+        let mut job = Job::for_tests(
+            name,
+            JobSettings {
+                pool: Some(pool.name.clone()),
+                ..JobSettings::default()
+            },
+        );
+        job.pool = Some(pool.clone());
+        Arc::new(job)
+    }
+
+    #[test]
+    fn pool_at_capacity() {
+        let linux = pool("linux", 2);
+        let backend = job_on_pool("backend", &linux);
+        let frontend = job_on_pool("frontend", &linux);
+
+        let running_builds = running(&[("backend/1", Some(("linux", 0)))]);
+        assert_eq!(free_pool_slot(&linux, &running_builds), Some(1));
+        assert!(can_start(&frontend, &running_builds));
+
+        let running_builds = running(&[
+            ("backend/1", Some(("linux", 0))),
+            ("frontend/1", Some(("linux", 1))),
+        ]);
+        assert_eq!(free_pool_slot(&linux, &running_builds), None);
+        assert!(!can_start(&backend, &running_builds));
+        assert!(!can_start(&frontend, &running_builds));
+        // a job without a pool is not held up by it
+        assert!(can_start(
+            &job("docs", JobSettings::default()),
+            &running_builds
+        ));
+    }
+
+    #[test]
+    fn pool_slots_are_reused() {
+        let linux = pool("linux", 3);
+        let backend = job_on_pool("backend", &linux);
+
+        let mut running_builds = running(&[
+            ("backend/1", Some(("linux", 0))),
+            ("backend/2", Some(("linux", 1))),
+            ("backend/3", Some(("linux", 2))),
+        ]);
+        assert!(!can_start(&backend, &running_builds));
+        running_builds.remove("backend/2");
+        assert_eq!(free_pool_slot(&linux, &running_builds), Some(1));
+        assert!(can_start(&backend, &running_builds));
+        running_builds.remove("backend/1");
+        // the lowest free slot comes first
+        assert_eq!(free_pool_slot(&linux, &running_builds), Some(0));
+    }
+
+    #[test]
+    fn pools_do_not_share_slots() {
+        let linux = pool("linux", 1);
+        let windows = pool("windows", 1);
+        let running_builds = running(&[("backend/1", Some(("linux", 0)))]);
+        assert_eq!(free_pool_slot(&linux, &running_builds), None);
+        assert_eq!(free_pool_slot(&windows, &running_builds), Some(0));
+        assert!(can_start(
+            &job_on_pool("installer", &windows),
+            &running_builds
+        ));
+    }
+
+    #[test]
+    fn pools_changed_by_a_reload() {
+        // the builds started before the reload keep their slots of the previous pool
+        let running_builds = running(&[
+            ("backend/1", Some(("linux", 0))),
+            ("backend/2", Some(("linux", 3))),
+        ]);
+        let shrunk = pool("linux", 2);
+        assert_eq!(free_pool_slot(&shrunk, &running_builds), Some(1));
+        let shrunk = pool("linux", 1);
+        assert_eq!(free_pool_slot(&shrunk, &running_builds), None);
+        let renamed = pool("linux-large", 1);
+        assert_eq!(free_pool_slot(&renamed, &running_builds), Some(0));
+    }
+
+    #[test]
+    fn jobs_on_unknown_pools() {
+        let job_folder =
+            std::env::temp_dir().join(format!("formica-pool-job-{}", std::process::id()));
+        fs::create_dir_all(&job_folder).unwrap();
+        fs::write(job_folder.join(JOB_SETTINGS_FILE), "pool = \"linux\"\n").unwrap();
+
+        let job = load_job(
+            "backend".to_string(),
+            job_folder.clone(),
+            &[pool("linux", 2)],
+        )
+        .unwrap();
+        assert_eq!(job.pool.unwrap().name, "linux");
+        // e.g. the pool folder was removed, and the jobs reloaded
+        let load_err = load_job(
+            "backend".to_string(),
+            job_folder.clone(),
+            &[pool("windows", 1)],
+        )
+        .err()
+        .unwrap();
+        assert_eq!(load_err, "unknown agent pool linux");
+        assert!(load_job("backend".to_string(), job_folder.clone(), &[]).is_err());
+        fs::remove_dir_all(&job_folder).unwrap();
+    }
+
+    #[test]
+    fn max_concurrent_jobs() {
+        let settings = SharedSettings::default();
+        settings.write().unwrap().max_concurrent_jobs = Some(2);
+        assert!(has_free_slot(&settings, &running(&[("docs/1", None)])));
+        assert!(!has_free_slot(
+            &settings,
+            &running(&[("docs/1", None), ("backend/4", None)])
+        ));
+        settings.write().unwrap().max_concurrent_jobs = None;
+        assert!(has_free_slot(
+            &settings,
+            &running(&[("docs/1", None), ("backend/4", None)])
+        ));
+    }
 }
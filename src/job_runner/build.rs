@@ -0,0 +1,485 @@
+use super::protocol::OutputStream;
+use super::{StepResult, StepStatus};
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const WORKSPACE: &str = "workspace";
+pub const STDOUT_LOG: &str = "stdout.log";
+pub const STDERR_LOG: &str = "stderr.log";
+pub const METADATA: &str = "metadata";
+/// The output of the scripts run once the build has finished (see `post_build`).
+pub const HOOKS_LOG: &str = "hooks.log";
+/// How long to wait before reading more of the log of a running build.
+const LOG_FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The record of a single build of a job, kept in `<build dir>/<job name>/<build number>/`.
+pub struct BuildRecord {
+    pub job_name: String,
+    pub number: u64,
+    pub dir: PathBuf,
+    pub trigger_source: String,
+    pub start_time: SystemTime,
+    pub end_time: Option<SystemTime>,
+    pub status: BuildStatus,
+    pub steps: Vec<StepResult>,
+    /// What went wrong with the agent of the build, rather than with its steps, if anything.
+    pub infrastructure_error: Option<String>,
+    /// Whether another attempt at the build was scheduled, since it failed because of its agent.
+    pub retried: bool,
+}
+
+impl BuildRecord {
+    /// Creates the folder of the next build of the job, along with its workspace.
+    pub fn create(build_root: &Path, job_name: &str, trigger_source: &str) -> io::Result<Self> {
+        let job_dir = build_root.join(job_name);
+        fs::create_dir_all(&job_dir)?;
+        let mut number = last_build_number(&job_dir)? + 1;
+        // another thread may be creating a build of the same job, so the folder
+        // creation itself decides which number each build gets
+        let dir = loop {
+            let dir = job_dir.join(number.to_string());
+            match fs::create_dir(&dir) {
+                Ok(()) => break dir,
+                Err(create_err) if create_err.kind() == io::ErrorKind::AlreadyExists => number += 1,
+                Err(create_err) => return Err(create_err),
+            }
+        };
+        fs::create_dir(dir.join(WORKSPACE))?;
+        let record = BuildRecord {
+            job_name: job_name.to_string(),
+            number,
+            dir,
+            trigger_source: trigger_source.to_string(),
+            start_time: SystemTime::now(),
+            end_time: None,
+            status: BuildStatus::Running,
+            steps: Vec::new(),
+            infrastructure_error: None,
+            retried: false,
+        };
+        record.write_metadata()?;
+        Ok(record)
+    }
+
+    /// Identifies the build among all builds, e.g. `backend/unit_tests/12`.
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.job_name, self.number)
+    }
+
+    pub fn workspace(&self) -> PathBuf {
+        self.dir.join(WORKSPACE)
+    }
+
+    pub fn artifacts(&self) -> PathBuf {
+        self.dir.join(super::ARTIFACTS)
+    }
+
+    /// Opens the log files of the build for appending. Every line goes straight
+    /// to the files, so the logs can be followed while the build runs.
+    pub fn open_logs(&self) -> io::Result<BuildLogs> {
+        let open_log = |log_name| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join(log_name))
+        };
+        Ok(BuildLogs {
+            stdout: open_log(STDOUT_LOG)?,
+            stderr: open_log(STDERR_LOG)?,
+        })
+    }
+
+    /// Opens the log of the post-build scripts for appending, as both their output and errors.
+    pub fn open_hooks_log(&self) -> io::Result<BuildLogs> {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(HOOKS_LOG))?;
+        Ok(BuildLogs {
+            stderr: log.try_clone()?,
+            stdout: log,
+        })
+    }
+
+    pub fn finish(&mut self, status: BuildStatus, steps: Vec<StepResult>) -> io::Result<()> {
+        self.status = status;
+        self.steps = steps;
+        self.end_time = Some(SystemTime::now());
+        self.write_metadata()
+    }
+
+    /// Writes the metadata file as `key=value` lines, replacing it atomically.
+    pub fn write_metadata(&self) -> io::Result<()> {
+        let mut metadata = String::new();
+        metadata.push_str(&format!("job={}\n", self.job_name));
+        metadata.push_str(&format!("number={}\n", self.number));
+        metadata.push_str(&format!("trigger={}\n", self.trigger_source));
+        metadata.push_str(&format!("start_time={}\n", unix_time(self.start_time)));
+        if let Some(end_time) = self.end_time {
+            metadata.push_str(&format!("end_time={}\n", unix_time(end_time)));
+        }
+        metadata.push_str(&format!("status={}\n", self.status));
+        if let Some(infrastructure_error) = &self.infrastructure_error {
+            metadata.push_str(&format!(
+                "infrastructure_error={}\n",
+                infrastructure_error.replace('\n', " ")
+            ));
+        }
+        if self.retried {
+            metadata.push_str("retried=true\n");
+        }
+        for step in self.steps.iter() {
+            metadata.push_str(&format!("step.{}.status={}\n", step.name, step.status));
+            metadata.push_str(&format!(
+                "step.{}.duration={:.3}\n",
+                step.name,
+                step.duration.as_secs_f64()
+            ));
+        }
+        replace_metadata(&self.dir, &metadata)
+    }
+}
+
+pub struct BuildLogs {
+    stdout: File,
+    stderr: File,
+}
+
+impl BuildLogs {
+    pub fn write_line(&mut self, stream: OutputStream, line: &str) {
+        let log = match stream {
+            OutputStream::Stdout => &mut self.stdout,
+            OutputStream::Stderr => &mut self.stderr,
+        };
+        if let Err(write_err) = writeln!(log, "{}", line) {
+            warn!("Failed to write to the build log: {}", write_err);
+        }
+    }
+
+    pub fn into_files(self) -> (File, File) {
+        (self.stdout, self.stderr)
+    }
+
+    /// Another handle on the stderr log, for writing to it from a separate thread.
+    pub fn stderr_handle(&self) -> io::Result<File> {
+        self.stderr.try_clone()
+    }
+}
+
+/// A build as recorded in its metadata file, for reporting.
+pub struct BuildInfo {
+    pub job_name: String,
+    pub number: u64,
+    pub trigger_source: String,
+    /// In seconds since the Unix epoch.
+    pub start_time: u64,
+    pub end_time: Option<u64>,
+    pub status: String,
+    pub infrastructure_error: Option<String>,
+    pub retried: bool,
+    pub steps: Vec<StepInfo>,
+}
+
+pub struct StepInfo {
+    pub name: String,
+    pub status: String,
+    pub duration_secs: f64,
+}
+
+impl BuildInfo {
+    /// Reads the metadata of a build of the job, e.g. while it runs or after it finished.
+    pub fn read(build_root: &Path, job_name: &str, number: u64) -> io::Result<Self> {
+        let dir = build_root.join(job_name).join(number.to_string());
+        let metadata = fs::read_to_string(dir.join(METADATA))?;
+        let mut info = BuildInfo {
+            job_name: job_name.to_string(),
+            number,
+            trigger_source: String::new(),
+            start_time: 0,
+            end_time: None,
+            status: String::new(),
+            infrastructure_error: None,
+            retried: false,
+            steps: Vec::new(),
+        };
+        for (key, value) in metadata.lines().filter_map(|line| line.split_once('=')) {
+            match key {
+                "trigger" => info.trigger_source = value.to_string(),
+                "start_time" => info.start_time = value.parse().unwrap_or(0),
+                "end_time" => info.end_time = value.parse().ok(),
+                "status" => info.status = value.to_string(),
+                "infrastructure_error" => info.infrastructure_error = Some(value.to_string()),
+                "retried" => info.retried = value == "true",
+                _ => {
+                    // step names can contain dots, but the attribute names cannot
+                    let (step_name, attribute) = match key
+                        .strip_prefix("step.")
+                        .and_then(|step_key| step_key.rsplit_once('.'))
+                    {
+                        Some(step_key) => step_key,
+                        None => continue,
+                    };
+                    let index = match info.steps.iter().position(|step| step.name == step_name) {
+                        Some(index) => index,
+                        None => {
+                            info.steps.push(StepInfo {
+                                name: step_name.to_string(),
+                                status: String::new(),
+                                duration_secs: 0.0,
+                            });
+                            info.steps.len() - 1
+                        }
+                    };
+                    let step = &mut info.steps[index];
+                    match attribute {
+                        "status" => step.status = value.to_string(),
+                        "duration" => step.duration_secs = value.parse().unwrap_or(0.0),
+                        _ => (),
+                    }
+                }
+            }
+        }
+        Ok(info)
+    }
+
+    pub fn id(&self) -> String {
+        format!("{}/{}", self.job_name, self.number)
+    }
+
+    pub fn dir(&self, build_root: &Path) -> PathBuf {
+        build_root
+            .join(&self.job_name)
+            .join(self.number.to_string())
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.status == BuildStatus::Running.to_string()
+    }
+
+    /// How long the build ran for, or has been running for.
+    pub fn duration_secs(&self) -> u64 {
+        self.end_time
+            .unwrap_or_else(|| unix_time(SystemTime::now()))
+            .saturating_sub(self.start_time)
+    }
+}
+
+/// Reads the last builds of the job, newest first. Builds without readable metadata are
+/// skipped.
+pub fn list_builds(build_root: &Path, job_name: &str, limit: usize) -> Vec<BuildInfo> {
+    let mut numbers = build_numbers(&build_root.join(job_name)).unwrap_or_default();
+    numbers.sort_unstable_by(|number, other_number| other_number.cmp(number));
+    numbers
+        .into_iter()
+        .take(limit)
+        .filter_map(|number| BuildInfo::read(build_root, job_name, number).ok())
+        .collect()
+}
+
+/// Reads a log of the build as it grows, handing over each new chunk, until the build
+/// finishes.
+pub fn follow_log(
+    log: &mut File,
+    build_root: &Path,
+    build: &BuildInfo,
+    mut send: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut buffer = [0; 8192];
+    let mut running = build.is_running();
+    loop {
+        let read = log.read(&mut buffer)?;
+        if read > 0 {
+            send(&buffer[..read])?;
+        } else if running {
+            // the build may have written more before finishing, so read once more
+            running = BuildInfo::read(build_root, &build.job_name, build.number)
+                .map(|build| build.is_running())
+                .unwrap_or(false);
+            if running {
+                thread::sleep(LOG_FOLLOW_INTERVAL);
+            }
+        } else {
+            return Ok(());
+        }
+    }
+}
+
+/// Records the builds left `RUNNING` by an orchestrator that did not stop (e.g. it crashed
+/// or was killed) as `ABORTED`, ending when their logs were last written to. Returns the ids
+/// of these builds. Only call this before starting to run builds.
+pub fn abort_stale_builds(build_root: &Path) -> Vec<String> {
+    let mut aborted_builds = Vec::new();
+    abort_stale_builds_in(build_root, build_root, &mut aborted_builds);
+    aborted_builds
+}
+
+fn abort_stale_builds_in(build_root: &Path, dir: &Path, aborted_builds: &mut Vec<String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    let subdirs = entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
+        .map(|entry| entry.path());
+    for subdir in subdirs {
+        // job names can contain slashes, so job folders can hold other job folders, but
+        // build folders are not searched any further
+        if !subdir.join(METADATA).is_file() {
+            abort_stale_builds_in(build_root, &subdir, aborted_builds);
+            continue;
+        }
+        let build_id = subdir
+            .strip_prefix(build_root)
+            .unwrap_or(&subdir)
+            .to_string_lossy()
+            .into_owned();
+        match abort_if_running(&subdir) {
+            Ok(true) => aborted_builds.push(build_id),
+            Ok(false) => (),
+            Err(abort_err) => warn!("Failed to check build {}: {}", build_id, abort_err),
+        }
+    }
+}
+
+/// Rewrites the status of the build in the folder to `ABORTED` if it is `RUNNING`, returning
+/// whether it was.
+fn abort_if_running(dir: &Path) -> io::Result<bool> {
+    let metadata = fs::read_to_string(dir.join(METADATA))?;
+    let running_status = format!("status={}", BuildStatus::Running);
+    if !metadata.lines().any(|line| line == running_status) {
+        return Ok(false);
+    }
+    let end_time = [METADATA, STDOUT_LOG, STDERR_LOG]
+        .iter()
+        .filter_map(|file_name| fs::metadata(dir.join(file_name)).ok()?.modified().ok())
+        .max()
+        .unwrap_or_else(SystemTime::now);
+    let mut aborted = String::new();
+    for line in metadata.lines() {
+        if line == running_status {
+            aborted.push_str(&format!("end_time={}\n", unix_time(end_time)));
+            aborted.push_str(&format!("status={}\n", BuildStatus::Aborted));
+        } else if !line.starts_with("end_time=") {
+            aborted.push_str(line);
+            aborted.push('\n');
+        }
+    }
+    replace_metadata(dir, &aborted)?;
+    Ok(true)
+}
+
+/// Replaces the metadata file of the build folder atomically, so that it is never read
+/// half-written.
+fn replace_metadata(dir: &Path, metadata: &str) -> io::Result<()> {
+    let temporary_file = dir.join(format!(".{}", METADATA));
+    fs::write(&temporary_file, metadata)?;
+    fs::rename(temporary_file, dir.join(METADATA))
+}
+
+fn build_numbers(job_dir: &Path) -> io::Result<Vec<u64>> {
+    Ok(fs::read_dir(job_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false))
+        .filter_map(|entry| entry.file_name().to_str()?.parse::<u64>().ok())
+        .collect())
+}
+
+fn last_build_number(job_dir: &Path) -> io::Result<u64> {
+    Ok(build_numbers(job_dir)?.into_iter().max().unwrap_or(0))
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BuildStatus {
+    Running,
+    Success,
+    /// One of the steps returned a non-zero exit code.
+    Failure,
+    /// The agent could not be started, or did not follow the protocol: an infrastructure
+    /// failure, which the job may retry, rather than a failure of its steps.
+    AgentFailure,
+    /// The build was stopped by a shutdown of the orchestrator.
+    Aborted,
+    /// The build was stopped on request.
+    Cancelled,
+    /// The build, or one of its steps, ran past its timeout.
+    TimedOut,
+}
+
+impl BuildStatus {
+    /// The overall status of a build whose steps ran with the given results.
+    pub fn from_steps(steps: &[StepResult]) -> Self {
+        steps
+            .iter()
+            .map(|step| match step.status {
+                StepStatus::Success => BuildStatus::Success,
+                StepStatus::Failed(_) => BuildStatus::Failure,
+                StepStatus::AgentFailure => BuildStatus::AgentFailure,
+                StepStatus::Interrupted => BuildStatus::Aborted,
+                StepStatus::TimedOut => BuildStatus::TimedOut,
+            })
+            .find(|status| *status != BuildStatus::Success)
+            .unwrap_or(BuildStatus::Success)
+    }
+}
+
+impl fmt::Display for BuildStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self {
+            BuildStatus::Running => "RUNNING",
+            BuildStatus::Success => "SUCCESS",
+            BuildStatus::Failure => "FAILURE",
+            BuildStatus::AgentFailure => "AGENT_FAILURE",
+            BuildStatus::Aborted => "ABORTED",
+            BuildStatus::Cancelled => "CANCELLED",
+            BuildStatus::TimedOut => "TIMED_OUT",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_builds_are_aborted() {
+        let build_root =
+            std::env::temp_dir().join(format!("formica-stale-builds-{}", std::process::id()));
+        let stale = BuildRecord::create(&build_root, "backend/unit_tests", "cli").unwrap();
+        let mut logs = stale.open_logs().unwrap();
+        logs.write_line(OutputStream::Stdout, "compiling");
+        let mut finished = BuildRecord::create(&build_root, "backend/unit_tests", "cli").unwrap();
+        finished.finish(BuildStatus::Success, Vec::new()).unwrap();
+        let mut nested = BuildRecord::create(&build_root, "backend", "cli").unwrap();
+        nested.retried = true;
+        nested.write_metadata().unwrap();
+
+        let mut aborted_builds = abort_stale_builds(&build_root);
+        aborted_builds.sort();
+        assert_eq!(aborted_builds, vec!["backend/1", "backend/unit_tests/1"]);
+        let stale = BuildInfo::read(&build_root, "backend/unit_tests", 1).unwrap();
+        assert_eq!(stale.status, "ABORTED");
+        assert!(stale.end_time.is_some());
+        assert_eq!(stale.trigger_source, "cli");
+        let nested = BuildInfo::read(&build_root, "backend", 1).unwrap();
+        assert_eq!(nested.status, "ABORTED");
+        assert!(nested.retried);
+        let finished = BuildInfo::read(&build_root, "backend/unit_tests", 2).unwrap();
+        assert_eq!(finished.status, "SUCCESS");
+
+        assert!(abort_stale_builds(&build_root).is_empty());
+        fs::remove_dir_all(&build_root).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+//! The control socket, through which the command-line client (e.g. `formica-ci status`)
+//! talks to a running orchestrator.
+//!
+//! Each connection carries a single request: the client writes a command line, possibly
+//! followed by more lines, and then closes its side of the connection. The orchestrator
+//! answers `OK` or `ERROR <message>` on the first line, followed by the output of the command:
+//!
+//! * `TRIGGER <job name>`, followed by the parameters of the build as `KEY=value` lines.
+//! * `STATUS`: the running builds, and the builds waiting for a free slot.
+//! * `LOG <stdout|stderr> <build id>`: a log of the build, followed until the build finishes.
+//!   A job name can be given instead of a build id, for the last build of the job.
+//! * `CANCEL <build id>`: cancels a running build. A job name can be given instead of a
+//!   build id, to remove the builds of the job waiting for a free slot.
+//! * `SHUTDOWN <slow|immediate>`: starts a shutdown, like pressing Ctrl + C.
+
+use super::build::{self, BuildInfo};
+use super::queue::{self, JobTrigger, REVISION_PARAMETER};
+use super::settings::SharedSettings;
+use super::{find_job, is_safe_job_name, OrchestratorRequest, OrchestratorStatus, SharedJobs};
+
+use crossbeam_channel::{bounded, Sender};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// Requests larger than this are cut short.
+const MAX_REQUEST_SIZE: u64 = 1024 * 1024;
+/// How long to wait for the orchestrator to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the command handlers need, cloned for every connection.
+#[derive(Clone)]
+pub struct ControlContext {
+    pub settings: SharedSettings,
+    pub jobs: SharedJobs,
+    pub request_notifier: Sender<OrchestratorRequest>,
+    pub slow_shutdown: Sender<()>,
+    pub immediate_shutdown: Sender<()>,
+}
+
+/// Starts listening on the socket, and serves the commands from a separate thread.
+pub fn launch_control_server(socket_path: &Path, context: ControlContext) -> io::Result<()> {
+    // a socket left behind by a previous run is replaced, but not one that is still in use
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                "another Formica CI is listening on it",
+            ));
+        }
+        fs::remove_file(socket_path)?;
+    }
+    let listener = UnixListener::bind(socket_path)?;
+    // anyone who can connect can trigger builds and shut Formica down
+    fs::set_permissions(socket_path, fs::Permissions::from_mode(0o600))?;
+    info!("Listening for commands on {}", socket_path.display());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let context = context.clone();
+                    // following a log takes as long as the build, so every connection
+                    // gets its own thread
+                    thread::spawn(move || handle_connection(&context, stream));
+                }
+                Err(accept_err) => warn!("Failed to accept a command: {}", accept_err),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle_connection(context: &ControlContext, mut stream: UnixStream) {
+    let mut request = String::new();
+    if let Err(read_err) = (&stream)
+        .take(MAX_REQUEST_SIZE)
+        .read_to_string(&mut request)
+    {
+        debug!("Failed to read a command: {}", read_err);
+        return;
+    }
+    let (command_line, rest) = request.split_once('\n').unwrap_or((&request, ""));
+    let (command, argument) = command_line.split_once(' ').unwrap_or((command_line, ""));
+    debug!("Received command {}", command_line);
+    let result = match command {
+        "TRIGGER" => trigger_build(context, argument, rest),
+        "STATUS" => list_builds(context),
+        "LOG" => return follow_log(context, argument, stream),
+        "CANCEL" => cancel_build(context, argument),
+        "SHUTDOWN" => shut_down(context, argument),
+        _ => Err(format!("unknown command {}", command)),
+    };
+    let response = match result {
+        Ok(output) => format!("OK\n{}", output),
+        Err(message) => format!("ERROR {}\n", message),
+    };
+    if let Err(write_err) = stream.write_all(response.as_bytes()) {
+        debug!("Failed to answer command {}: {}", command_line, write_err);
+    }
+}
+
+/// Queues a build of the job, with the parameters given as in a trigger file.
+fn trigger_build(
+    context: &ControlContext,
+    job_name: &str,
+    parameters: &str,
+) -> Result<String, String> {
+    if find_job(&context.jobs, job_name).is_none() {
+        return Err(format!("unknown job {}", job_name));
+    }
+    let mut parameters = queue::parse_parameters(parameters)
+        .map_err(|parse_err| format!("invalid parameters: {}", parse_err))?;
+    let revision = parameters.remove(REVISION_PARAMETER);
+    let mut trigger = JobTrigger::new(job_name, String::from("cli"), revision);
+    trigger.parameters = parameters;
+    info!("Queueing job {} (command line)", job_name);
+    let (reply_notifier, reply_listener) = bounded(1);
+    context
+        .request_notifier
+        .send(OrchestratorRequest::QueueBuild {
+            trigger,
+            reply: reply_notifier,
+        })
+        .map_err(|_| "the orchestrator is not running".to_string())?;
+    match reply_listener.recv_timeout(REQUEST_TIMEOUT) {
+        Ok(true) => Ok(format!("Queued a build of {}\n", job_name)),
+        Ok(false) => Err(format!(
+            "the build of {} was refused, Formica is shutting down",
+            job_name
+        )),
+        Err(_) => Err("the orchestrator did not answer".to_string()),
+    }
+}
+
+fn list_builds(context: &ControlContext) -> Result<String, String> {
+    let (reply_notifier, reply_listener) = bounded(1);
+    context
+        .request_notifier
+        .send(OrchestratorRequest::ListBuilds {
+            reply: reply_notifier,
+        })
+        .map_err(|_| "the orchestrator is not running".to_string())?;
+    let OrchestratorStatus {
+        running_builds,
+        pending_builds,
+    } = reply_listener
+        .recv_timeout(REQUEST_TIMEOUT)
+        .map_err(|_| "the orchestrator did not answer".to_string())?;
+    let build_dir = context.settings.read().unwrap().build_dir.clone();
+    let mut output = String::new();
+    if running_builds.is_empty() {
+        output.push_str("No builds are running.\n");
+    } else {
+        output.push_str("Running builds:\n");
+    }
+    for build_id in running_builds {
+        let build = build_id
+            .rsplit_once('/')
+            .and_then(|(job_name, number)| Some((job_name, number.parse().ok()?)))
+            .and_then(|(job_name, number)| BuildInfo::read(&build_dir, job_name, number).ok());
+        let _ = match build {
+            Some(build) => writeln!(
+                output,
+                "  {} (running for {}s, triggered by {})",
+                build_id,
+                build.duration_secs(),
+                build.trigger_source
+            ),
+            None => writeln!(output, "  {}", build_id),
+        };
+    }
+    if !pending_builds.is_empty() {
+        output.push_str("Waiting for a free slot:\n");
+    }
+    for (job_name, trigger_source) in pending_builds {
+        let _ = writeln!(output, "  {} (triggered by {})", job_name, trigger_source);
+    }
+    Ok(output)
+}
+
+fn cancel_build(context: &ControlContext, build_id: &str) -> Result<String, String> {
+    if find_job(&context.jobs, build_id).is_some() {
+        return cancel_waiting_builds(context, build_id);
+    }
+    let build = read_build(context, build_id)?;
+    let (reply_notifier, reply_listener) = bounded(1);
+    context
+        .request_notifier
+        .send(OrchestratorRequest::CancelBuild {
+            build_id: build.id(),
+            reply: reply_notifier,
+        })
+        .map_err(|_| "the orchestrator is not running".to_string())?;
+    match reply_listener.recv_timeout(REQUEST_TIMEOUT) {
+        Ok(true) => Ok(format!("Cancelling build {}\n", build.id())),
+        Ok(false) => Err(format!("build {} is not running", build.id())),
+        Err(_) => Err("the orchestrator did not answer".to_string()),
+    }
+}
+
+fn cancel_waiting_builds(context: &ControlContext, job_name: &str) -> Result<String, String> {
+    let (reply_notifier, reply_listener) = bounded(1);
+    context
+        .request_notifier
+        .send(OrchestratorRequest::CancelWaitingBuilds {
+            job_name: job_name.to_string(),
+            reply: reply_notifier,
+        })
+        .map_err(|_| "the orchestrator is not running".to_string())?;
+    match reply_listener.recv_timeout(REQUEST_TIMEOUT) {
+        Ok(0) => Err(format!("no build of {} is waiting", job_name)),
+        Ok(cancelled) => Ok(format!(
+            "Removed {} waiting build(s) of {}\n",
+            cancelled, job_name
+        )),
+        Err(_) => Err("the orchestrator did not answer".to_string()),
+    }
+}
+
+fn shut_down(context: &ControlContext, mode: &str) -> Result<String, String> {
+    // the shutdown channels only hold one message, and a full one means that the
+    // shutdown was already requested
+    match mode {
+        "slow" => {
+            info!("Starting slow shutdown (command line): No more jobs will be accepted...");
+            let _ = context.slow_shutdown.try_send(());
+            Ok("Started a slow shutdown: the running builds will finish first\n".to_string())
+        }
+        "immediate" => {
+            info!("Triggering immediate shutdown (command line): Cleaning up agents...");
+            let _ = context.immediate_shutdown.try_send(());
+            Ok("Started an immediate shutdown: the running builds are being aborted\n".to_string())
+        }
+        _ => Err(format!("unknown shutdown mode {}", mode)),
+    }
+}
+
+/// Sends a log of the build as it is written, until the build finishes.
+fn follow_log(context: &ControlContext, argument: &str, mut stream: UnixStream) {
+    let followed = match open_log(context, argument) {
+        Ok((build, mut log)) => stream.write_all(b"OK\n").and_then(|_| {
+            let build_dir = context.settings.read().unwrap().build_dir.clone();
+            build::follow_log(&mut log, &build_dir, &build, |data| stream.write_all(data))
+        }),
+        Err(message) => stream.write_all(format!("ERROR {}\n", message).as_bytes()),
+    };
+    if let Err(write_err) = followed {
+        debug!("Stopped sending the log of {}: {}", argument, write_err);
+    }
+}
+
+fn open_log(context: &ControlContext, argument: &str) -> Result<(BuildInfo, File), String> {
+    let (stream_name, target) = argument.split_once(' ').unwrap_or((argument, ""));
+    let log_name = match stream_name {
+        "stdout" => build::STDOUT_LOG,
+        "stderr" => build::STDERR_LOG,
+        _ => return Err(format!("unknown log {}", stream_name)),
+    };
+    let build = match find_job(&context.jobs, target) {
+        // a job name stands for its last build
+        Some(job) => {
+            let build_dir = context.settings.read().unwrap().build_dir.clone();
+            build::list_builds(&build_dir, &job.name, 1)
+                .pop()
+                .ok_or_else(|| format!("{} has no builds yet", job.name))?
+        }
+        None => read_build(context, target)?,
+    };
+    let build_dir = context.settings.read().unwrap().build_dir.clone();
+    let log = File::open(build.dir(&build_dir).join(log_name))
+        .map_err(|_| format!("build {} has no log", build.id()))?;
+    Ok((build, log))
+}
+
+/// Reads the metadata of the build with the given id (e.g. `backend/unit_tests/12`).
+fn read_build(context: &ControlContext, build_id: &str) -> Result<BuildInfo, String> {
+    let unknown_build = || format!("unknown build {}", build_id);
+    let (job_name, number) = match build_id.rsplit_once('/') {
+        Some((job_name, number)) if is_safe_job_name(job_name) => (job_name, number),
+        _ => return Err(unknown_build()),
+    };
+    let number = number.parse().map_err(|_| unknown_build())?;
+    let build_dir = context.settings.read().unwrap().build_dir.clone();
+    BuildInfo::read(&build_dir, job_name, number).map_err(|_| unknown_build())
+}
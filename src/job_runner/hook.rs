@@ -0,0 +1,427 @@
+//! Webhooks, received by the HTTP API at `/hook/<name>` and translated into builds by the
+//! `hook_<name>` script at the root of `formica_conf`, so that Formica does not need to
+//! know the payloads of any forge.
+//!
+//! The script gets the body of the request on its standard input and the headers as
+//! `FORMICA_HEADER_<NAME>` environment variables, and prints the builds to queue, one per
+//! line: the name of the job, followed by the parameters of the build as `KEY=value` words.
+//! A script still running after [`HOOK_TIMEOUT`] is killed, along with everything it started.
+
+use super::queue::{self, JobTrigger, REVISION_PARAMETER};
+use super::script;
+use super::{find_job, SharedJobs, CONFIG};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+pub const HOOK_PREFIX: &str = "hook_";
+/// Headers holding the HMAC-SHA256 signature of the body, in hexadecimal (optionally
+/// prefixed with `sha256=`), as sent by GitHub, Gitea and Gogs.
+const SIGNATURE_HEADERS: [&str; 3] = [
+    "X-Hub-Signature-256",
+    "X-Gitea-Signature",
+    "X-Gogs-Signature",
+];
+/// GitLab sends the secret itself instead of a signature.
+const TOKEN_HEADER: &str = "X-Gitlab-Token";
+/// How long a hook script can run, since the forges do not wait much longer for an answer
+/// (e.g. GitHub gives up after 10 seconds).
+pub const HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The builds requested by a hook script.
+pub struct HookOutcome {
+    pub triggers: Vec<JobTrigger>,
+    /// The lines of the output that could not be turned into a build.
+    pub rejected: Vec<String>,
+}
+
+/// Hook names end up in script names, so they are kept to letters, digits, `-` and `_`.
+pub fn is_valid_hook_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Checks that the request was signed with the secret, or carries it (for GitLab).
+pub fn verify_signature(secret: &[u8], body: &[u8], headers: &[(String, String)]) -> bool {
+    headers.iter().any(|(name, value)| {
+        if name.eq_ignore_ascii_case(TOKEN_HEADER) {
+            return constant_time_eq(value.as_bytes(), secret);
+        }
+        if !SIGNATURE_HEADERS
+            .iter()
+            .any(|header| name.eq_ignore_ascii_case(header))
+        {
+            return false;
+        }
+        let signature = match hex::decode(value.trim().trim_start_matches("sha256=")) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key size");
+        mac.update(body);
+        mac.verify_slice(&signature).is_ok()
+    })
+}
+
+/// Runs the script of the hook with the request, and reads the builds it asks for.
+pub fn run_hook(
+    name: &str,
+    body: Vec<u8>,
+    headers: &[(String, String)],
+    jobs: &SharedJobs,
+) -> Result<HookOutcome, HookError> {
+    let config_dir = PathBuf::from(CONFIG);
+    let hook_script = find_hook_script(&config_dir, name)?;
+    let mut environment = BTreeMap::new();
+    environment.insert("FORMICA_HOOK_NAME".to_string(), name.to_string());
+    for (header, value) in headers {
+        let variable = format!(
+            "FORMICA_HEADER_{}",
+            header
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                })
+                .collect::<String>()
+        );
+        // repeated headers are combined, as HTTP allows
+        environment
+            .entry(variable)
+            .and_modify(|values: &mut String| {
+                values.push_str(", ");
+                values.push_str(value);
+            })
+            .or_insert_with(|| value.clone());
+    }
+    let output = match script::execute_script_with_input(
+        &config_dir,
+        &hook_script,
+        &environment,
+        body,
+        HOOK_TIMEOUT,
+    ) {
+        Ok(Some(output)) => output,
+        Ok(None) => {
+            warn!(
+                "The script of hook {} was killed after running for {}s",
+                name,
+                HOOK_TIMEOUT.as_secs()
+            );
+            return Err(HookError {
+                kind: HookErrorKind::TimedOut,
+            });
+        }
+        Err(execution_err) => {
+            return Err(HookError {
+                kind: HookErrorKind::ExecutionFailed(execution_err),
+            })
+        }
+    };
+    if !output.status.success() {
+        warn!(
+            "The script of hook {} failed with {}: {}",
+            name,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return Err(HookError {
+            kind: HookErrorKind::ScriptFailed(output.status),
+        });
+    }
+    let mut outcome = HookOutcome {
+        triggers: Vec::new(),
+        rejected: Vec::new(),
+    };
+    let source = format!("hook:{}", name);
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_hook_line(line, &source, jobs) {
+            Ok(trigger) => outcome.triggers.push(trigger),
+            Err(reason) => {
+                warn!("Ignoring '{}' from hook {}: {}", line, name, reason);
+                outcome.rejected.push(line.to_string());
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+/// Reads a line of the output of a hook script: a job name and `KEY=value` parameters.
+fn parse_hook_line(line: &str, source: &str, jobs: &SharedJobs) -> Result<JobTrigger, String> {
+    let mut words = line.split_whitespace();
+    let job_name = words.next().unwrap();
+    if find_job(jobs, job_name).is_none() {
+        return Err(format!("unknown job {}", job_name));
+    }
+    let mut parameters = BTreeMap::new();
+    for word in words {
+        let (name, value) = word
+            .split_once('=')
+            .ok_or_else(|| format!("expected KEY=value, got {}", word))?;
+        queue::check_parameter_name(name).map_err(|parameter_err| parameter_err.to_string())?;
+        parameters.insert(name.to_string(), value.to_string());
+    }
+    let revision = parameters.remove(REVISION_PARAMETER);
+    let mut trigger = JobTrigger::new(job_name, source.to_string(), revision);
+    trigger.parameters = parameters;
+    Ok(trigger)
+}
+
+/// Finds `hook_<name>`, with or without an extension. Unlike the other scripts, the name
+/// must match exactly, so that e.g. `hook_git` does not pick up `hook_gitlab`.
+fn find_hook_script(config_dir: &PathBuf, name: &str) -> Result<String, HookError> {
+    let script_name = format!("{}{}", HOOK_PREFIX, name);
+    let with_extension = format!("{}.", script_name);
+    let scripts: Vec<String> = fs::read_dir(config_dir)
+        .map_err(|list_err| HookError {
+            kind: HookErrorKind::ExecutionFailed(list_err),
+        })?
+        .filter_map(|file| file.ok())
+        .filter(|file| file.file_type().map(|ft| ft.is_file()).unwrap_or(false))
+        .filter_map(|file| file.file_name().into_string().ok())
+        .filter(|file_name| *file_name == script_name || file_name.starts_with(&with_extension))
+        .collect();
+    match scripts.len() {
+        0 => Err(HookError {
+            kind: HookErrorKind::UnknownHook,
+        }),
+        1 => Ok(scripts.into_iter().next().unwrap()),
+        _ => Err(HookError {
+            kind: HookErrorKind::TooManyScriptsFound(scripts),
+        }),
+    }
+}
+
+fn constant_time_eq(value: &[u8], expected: &[u8]) -> bool {
+    value.len() == expected.len()
+        && value
+            .iter()
+            .zip(expected)
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[derive(Debug)]
+pub struct HookError {
+    pub kind: HookErrorKind,
+}
+
+#[derive(Debug)]
+pub enum HookErrorKind {
+    UnknownHook,
+    TooManyScriptsFound(Vec<String>),
+    ExecutionFailed(io::Error),
+    ScriptFailed(ExitStatus),
+    /// The script ran past [`HOOK_TIMEOUT`], and was killed.
+    TimedOut,
+}
+
+impl fmt::Display for HookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            HookErrorKind::UnknownHook => write!(f, "no such hook"),
+            HookErrorKind::TooManyScriptsFound(scripts) => {
+                write!(f, "too many scripts for the hook: {}", scripts.join(", "))
+            }
+            HookErrorKind::ExecutionFailed(execution_err) => {
+                write!(f, "the hook script could not be run: {}", execution_err)
+            }
+            HookErrorKind::ScriptFailed(status) => {
+                write!(f, "the hook script failed with {}", status)
+            }
+            HookErrorKind::TimedOut => write!(
+                f,
+                "the hook script was killed after running for {}s",
+                HOOK_TIMEOUT.as_secs()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::job_runner::Job;
+    use std::sync::{Arc, RwLock};
+
+    const SECRET: &[u8] = b"It's a Secret to Everybody";
+    const BODY: &[u8] = b"{\"ref\":\"refs/heads/main\"}";
+
+    fn signature(secret: &[u8], body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn headers(name: &str, value: &str) -> Vec<(String, String)> {
+        vec![
+            ("Content-Type".to_string(), "application/json".to_string()),
+            (name.to_string(), value.to_string()),
+        ]
+    }
+
+    fn jobs(names: &[&str]) -> SharedJobs {
+        Arc::new(RwLock::new(
+            names
+                .iter()
+                .map(|name| Arc::new(Job::for_tests(name, Default::default())))
+                .collect(),
+        ))
+    }
+
+    #[test]
+    fn signatures() {
+        let valid = signature(SECRET, BODY);
+        for header in &SIGNATURE_HEADERS {
+            let prefixed = format!("sha256={}", valid);
+            assert!(verify_signature(SECRET, BODY, &headers(header, &valid)));
+            assert!(verify_signature(SECRET, BODY, &headers(header, &prefixed)));
+        }
+        // header names are case-insensitive
+        assert!(verify_signature(
+            SECRET,
+            BODY,
+            &headers("x-hub-signature-256", &valid)
+        ));
+        assert!(verify_signature(
+            SECRET,
+            BODY,
+            &headers("X-GITEA-SIGNATURE", &valid)
+        ));
+    }
+
+    #[test]
+    fn invalid_signatures() {
+        let header = "X-Hub-Signature-256";
+        let other_secret = signature(b"another secret", BODY);
+        let other_body = signature(SECRET, b"{}");
+        assert!(!verify_signature(
+            SECRET,
+            BODY,
+            &headers(header, &other_secret)
+        ));
+        assert!(!verify_signature(
+            SECRET,
+            BODY,
+            &headers(header, &other_body)
+        ));
+        assert!(!verify_signature(SECRET, BODY, &headers(header, "")));
+        assert!(!verify_signature(SECRET, BODY, &headers(header, "not hex")));
+        // a valid signature in a header that is not checked
+        let valid = signature(SECRET, BODY);
+        assert!(!verify_signature(
+            SECRET,
+            BODY,
+            &headers("X-Signature", &valid)
+        ));
+        assert!(!verify_signature(SECRET, BODY, &[]));
+    }
+
+    #[test]
+    fn gitlab_tokens() {
+        let secret = std::str::from_utf8(SECRET).unwrap();
+        assert!(verify_signature(
+            SECRET,
+            BODY,
+            &headers(TOKEN_HEADER, secret)
+        ));
+        assert!(verify_signature(
+            SECRET,
+            BODY,
+            &headers("x-gitlab-token", secret)
+        ));
+        assert!(!verify_signature(SECRET, BODY, &headers(TOKEN_HEADER, "")));
+        assert!(!verify_signature(
+            SECRET,
+            BODY,
+            &headers(TOKEN_HEADER, "It's a secret to everybody")
+        ));
+        assert!(!verify_signature(
+            SECRET,
+            BODY,
+            &headers(TOKEN_HEADER, &format!("{} ", secret))
+        ));
+    }
+
+    #[test]
+    fn hook_lines() {
+        let jobs = jobs(&["backend/unit_tests", "docs"]);
+        let trigger = parse_hook_line(
+            "backend/unit_tests REVISION=4f2a9c1 BRANCH=main",
+            "hook:gitea",
+            &jobs,
+        )
+        .unwrap();
+        assert_eq!(trigger.job_name, "backend/unit_tests");
+        assert_eq!(trigger.source, "hook:gitea");
+        assert_eq!(trigger.revision.as_deref(), Some("4f2a9c1"));
+        assert_eq!(
+            trigger.parameters.into_iter().collect::<Vec<_>>(),
+            vec![("BRANCH".to_string(), "main".to_string())]
+        );
+
+        let trigger = parse_hook_line("docs", "hook:gitea", &jobs).unwrap();
+        assert_eq!(trigger.revision, None);
+        assert!(trigger.parameters.is_empty());
+    }
+
+    #[test]
+    fn invalid_hook_lines() {
+        let jobs = jobs(&["docs"]);
+        assert_eq!(
+            parse_hook_line("website", "hook:gitea", &jobs).err(),
+            Some("unknown job website".to_string())
+        );
+        assert_eq!(
+            parse_hook_line("docs main", "hook:gitea", &jobs).err(),
+            Some("expected KEY=value, got main".to_string())
+        );
+        assert!(parse_hook_line("docs BRANCH=main =main", "hook:gitea", &jobs).is_err());
+        assert!(parse_hook_line("docs BRANCH-NAME=main", "hook:gitea", &jobs).is_err());
+        assert!(parse_hook_line("docs FORMICA_BUILD_ID=3", "hook:gitea", &jobs).is_err());
+    }
+
+    #[test]
+    fn hook_scripts_match_exactly() {
+        let config_dir = std::env::temp_dir().join(format!("formica-hooks-{}", std::process::id()));
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(config_dir.join("hook_gitlab.sh"), "").unwrap();
+        assert!(matches!(
+            find_hook_script(&config_dir, "git").unwrap_err().kind,
+            HookErrorKind::UnknownHook
+        ));
+        assert_eq!(
+            find_hook_script(&config_dir, "gitlab").unwrap(),
+            "hook_gitlab.sh"
+        );
+
+        fs::write(config_dir.join("hook_git"), "").unwrap();
+        assert_eq!(find_hook_script(&config_dir, "git").unwrap(), "hook_git");
+        assert_eq!(
+            find_hook_script(&config_dir, "gitlab").unwrap(),
+            "hook_gitlab.sh"
+        );
+
+        fs::write(config_dir.join("hook_git.py"), "").unwrap();
+        assert!(matches!(
+            find_hook_script(&config_dir, "git").unwrap_err().kind,
+            HookErrorKind::TooManyScriptsFound(_)
+        ));
+        fs::remove_dir_all(&config_dir).unwrap();
+    }
+}
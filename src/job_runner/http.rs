@@ -0,0 +1,592 @@
+//! A small HTTP API to trigger and inspect builds, enabled by the `http` feature and the
+//! `http_address` setting. It also serves a read-only dashboard at `/` (see [`dashboard`]).
+//! Responses are JSON objects, except for the build logs:
+//!
+//! * `GET /jobs`: the jobs, with their steps.
+//! * `POST /jobs/<job name>/trigger`: queues a build of the job. The optional body is a JSON
+//!   object holding the build parameters, e.g. `{"VERSION": "1.2.0", "REVISION": "3f2a9c1"}`.
+//! * `POST /jobs/<job name>/cancel`: removes the builds of the job waiting for a free slot.
+//! * `GET /builds?job=<job name>&limit=<count>`: the builds, newest first (both parameters
+//!   are optional).
+//! * `GET /builds/<job name>/<number>`: a build, with its steps.
+//! * `GET /builds/<job name>/<number>/log?stream=stderr`: the standard output (or error) log
+//!   of a build, followed until the build finishes.
+//! * `POST /builds/<job name>/<number>/cancel`: cancels a running build.
+//! * `POST /hook/<name>`: a webhook, handled by the `hook_<name>` script (see [`hook`]).
+//!
+//! The other `POST` requests must be sent with `Content-Type: application/json`, since
+//! browsers only send those to another origin after a CORS preflight, which is not answered:
+//! this keeps web pages from triggering or cancelling builds through the browsers of their
+//! visitors. Errors are reported as `{"error": "<message>"}` with the matching status code.
+
+use super::build::{self, BuildInfo};
+use super::hook::{self, HookErrorKind};
+use super::queue::{self, JobTrigger, REVISION_PARAMETER};
+use super::settings::SharedSettings;
+use super::{find_job, is_safe_job_name, OrchestratorRequest, SharedJobs, SharedUpdaterStatus};
+
+mod dashboard;
+
+use crossbeam_channel::{bounded, Sender};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// How many builds are listed when the request does not say.
+const DEFAULT_BUILD_LIMIT: usize = 50;
+/// Request bodies larger than this are refused (this is the largest payload GitHub sends).
+const MAX_BODY_SIZE: u64 = 25 * 1024 * 1024;
+/// How long to wait for the orchestrator to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What the request handlers need, cloned for every request.
+#[derive(Clone)]
+struct ApiContext {
+    settings: SharedSettings,
+    jobs: SharedJobs,
+    updater_status: SharedUpdaterStatus,
+    request_notifier: Sender<OrchestratorRequest>,
+}
+
+/// A JSON response, along with its status code.
+type Reply = (u16, Value);
+
+/// Starts listening on the address, and serves the API from a separate thread.
+pub fn launch_http_server(
+    address: &str,
+    settings: SharedSettings,
+    jobs: SharedJobs,
+    updater_status: SharedUpdaterStatus,
+    request_notifier: Sender<OrchestratorRequest>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let server = Server::http(address)?;
+    info!("The HTTP API is listening on {}", address);
+    let context = ApiContext {
+        settings,
+        jobs,
+        updater_status,
+        request_notifier,
+    };
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let context = context.clone();
+            // following the log of a build takes as long as the build, so every request
+            // gets its own thread
+            thread::spawn(move || handle_request(&context, request));
+        }
+    });
+    Ok(())
+}
+
+fn handle_request(context: &ApiContext, mut request: Request) {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let path = match percent_decode(path) {
+        Some(path) => path,
+        None => return respond(request, error(400, "invalid URL encoding")),
+    };
+    debug!("HTTP API request: {} {}", request.method(), url);
+    if *request.method() == Method::Post
+        && !path.starts_with("/hook/")
+        && !has_json_content_type(&request)
+    {
+        return respond(
+            request,
+            error(
+                415,
+                "the request must have a Content-Type: application/json header",
+            ),
+        );
+    }
+    let reply = match (request.method(), path.as_str()) {
+        (Method::Get, "/") => return respond_html(request, dashboard::overview(context)),
+        (Method::Get, path) if path.starts_with("/ui/jobs/") => {
+            let job_name = &path["/ui/jobs/".len()..];
+            return respond_html(request, dashboard::job_page(context, job_name));
+        }
+        (Method::Get, path) if path.starts_with("/ui/builds/") => {
+            let build_id = &path["/ui/builds/".len()..];
+            return dashboard::build_page(context, request, build_id, query);
+        }
+        (Method::Get, "/jobs") => list_jobs(context),
+        (Method::Post, path) if path.starts_with("/jobs/") && path.ends_with("/trigger") => {
+            let job_name = &path["/jobs/".len()..path.len() - "/trigger".len()];
+            match read_body(&mut request) {
+                Ok(body) => trigger_build(context, job_name, &String::from_utf8_lossy(&body)),
+                Err(reply) => reply,
+            }
+        }
+        (Method::Post, path) if path.starts_with("/jobs/") && path.ends_with("/cancel") => {
+            let job_name = &path["/jobs/".len()..path.len() - "/cancel".len()];
+            cancel_waiting_builds(context, job_name)
+        }
+        (Method::Post, path) if path.starts_with("/hook/") => {
+            let hook_name = &path["/hook/".len()..];
+            let headers: Vec<(String, String)> = request
+                .headers()
+                .iter()
+                .map(|header| (header.field.to_string(), header.value.to_string()))
+                .collect();
+            match read_body(&mut request) {
+                Ok(body) => receive_hook(context, hook_name, body, &headers),
+                Err(reply) => reply,
+            }
+        }
+        (Method::Get, "/builds") => list_builds(context, query),
+        (method, path) if path.starts_with("/builds/") => {
+            let build_path = &path["/builds/".len()..];
+            match (method, build_path.rsplit_once('/')) {
+                (Method::Get, Some((build_id, "log"))) => {
+                    return follow_log(context, request, build_id, query)
+                }
+                (Method::Post, Some((build_id, "cancel"))) => cancel_build(context, build_id),
+                (Method::Get, _) => get_build(context, build_path),
+                _ => error(405, "method not allowed"),
+            }
+        }
+        _ => error(404, "not found"),
+    };
+    respond(request, reply);
+}
+
+fn list_jobs(context: &ApiContext) -> Reply {
+    let jobs: Vec<Value> = context
+        .jobs
+        .read()
+        .unwrap()
+        .iter()
+        .map(|job| {
+            json!({
+                "name": job.name,
+                "steps": job
+                    .steps
+                    .iter()
+                    .map(|step| step.to_string_lossy())
+                    .collect::<Vec<_>>(),
+                "schedule": job
+                    .schedule
+                    .iter()
+                    .map(|entry| entry.expression.as_str())
+                    .collect::<Vec<_>>(),
+                "polled": job.poll_script.is_some(),
+            })
+        })
+        .collect();
+    (200, json!({ "jobs": jobs }))
+}
+
+/// Queues a build of the job, with the parameters given as a JSON object of strings.
+fn trigger_build(context: &ApiContext, job_name: &str, body: &str) -> Reply {
+    if find_job(&context.jobs, job_name).is_none() {
+        return error(404, &format!("unknown job {}", job_name));
+    }
+    let mut parameters = BTreeMap::new();
+    if !body.trim().is_empty() {
+        let object = match serde_json::from_str::<Map<String, Value>>(body) {
+            Ok(object) => object,
+            Err(parse_err) => return error(400, &format!("expected a JSON object: {}", parse_err)),
+        };
+        for (name, value) in object {
+            let value = match value {
+                Value::String(value) => value,
+                _ => return error(400, &format!("the value of {} is not a string", name)),
+            };
+            let checked = queue::check_parameter_name(&name)
+                .and_then(|_| queue::check_parameter_value(&name, &value));
+            if let Err(parameter_err) = checked {
+                return error(400, &parameter_err.to_string());
+            }
+            parameters.insert(name, value);
+        }
+    }
+    let revision = parameters.remove(REVISION_PARAMETER);
+    let mut trigger = JobTrigger::new(job_name, String::from("http"), revision);
+    trigger.parameters = parameters;
+    info!("Queueing job {} (HTTP API)", job_name);
+    if let Err(reply) = queue_trigger(context, trigger) {
+        return reply;
+    }
+    (202, json!({ "job": job_name, "queued": true }))
+}
+
+/// Hands the trigger to the orchestrator, which refuses it during a shutdown (a trigger
+/// without a file could not be run after the next start).
+fn queue_trigger(context: &ApiContext, trigger: JobTrigger) -> Result<(), Reply> {
+    let job_name = trigger.job_name.clone();
+    let (reply_notifier, reply_listener) = bounded(1);
+    let request = OrchestratorRequest::QueueBuild {
+        trigger,
+        reply: reply_notifier,
+    };
+    if context.request_notifier.send(request).is_err() {
+        return Err(error(503, "the orchestrator is not running"));
+    }
+    match reply_listener.recv_timeout(REQUEST_TIMEOUT) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(error(
+            503,
+            &format!(
+                "the build of {} was refused, Formica is shutting down",
+                job_name
+            ),
+        )),
+        Err(_) => Err(error(503, "the orchestrator did not answer")),
+    }
+}
+
+/// Verifies the signature of a webhook, and queues the builds its script asks for.
+fn receive_hook(
+    context: &ApiContext,
+    hook_name: &str,
+    body: Vec<u8>,
+    headers: &[(String, String)],
+) -> Reply {
+    if !hook::is_valid_hook_name(hook_name) {
+        return error(404, &format!("unknown hook {}", hook_name));
+    }
+    let secret_file = context.settings.read().unwrap().hook_secret_file.clone();
+    if let Some(secret_file) = secret_file {
+        // read on every request, so that the secret can be changed without a restart
+        let secret = match fs::read_to_string(&secret_file) {
+            Ok(secret) => secret,
+            Err(read_err) => {
+                error!(
+                    "Failed to read the hook secret from {}: {}",
+                    secret_file.display(),
+                    read_err
+                );
+                return error(500, "the hook secret could not be read");
+            }
+        };
+        // anyone can compute a signature with an empty key, or send an empty token
+        if secret.trim().is_empty() {
+            error!(
+                "The hook secret in {} is empty, refusing all hooks",
+                secret_file.display()
+            );
+            return error(500, "the hook secret is empty");
+        }
+        if !hook::verify_signature(secret.trim_end().as_bytes(), &body, headers) {
+            warn!(
+                "Rejected a request to hook {} with an invalid signature",
+                hook_name
+            );
+            return error(401, "invalid signature");
+        }
+    }
+    let outcome = match hook::run_hook(hook_name, body, headers, &context.jobs) {
+        Ok(outcome) => outcome,
+        Err(hook_err) => {
+            let status = match hook_err.kind {
+                HookErrorKind::UnknownHook => 404,
+                HookErrorKind::TimedOut => 504,
+                _ => 500,
+            };
+            return error(status, &format!("hook {}: {}", hook_name, hook_err));
+        }
+    };
+    let mut queued = Vec::new();
+    let mut refused = Vec::new();
+    let mut refusal = None;
+    for trigger in outcome.triggers {
+        info!("Queueing job {} (hook {})", trigger.job_name, hook_name);
+        let build = json!({ "job": trigger.job_name, "revision": trigger.revision });
+        match queue_trigger(context, trigger) {
+            Ok(()) => queued.push(build),
+            Err(reply) => {
+                refused.push(build);
+                refusal = Some(reply);
+            }
+        }
+    }
+    match refusal {
+        // nothing was queued, so the forge can deliver the hook again later
+        Some(reply) if queued.is_empty() => reply,
+        // delivering the hook again would queue the accepted builds twice
+        Some(_) => (
+            200,
+            json!({ "queued": queued, "refused": refused, "rejected": outcome.rejected }),
+        ),
+        None => (
+            202,
+            json!({ "queued": queued, "rejected": outcome.rejected }),
+        ),
+    }
+}
+
+fn list_builds(context: &ApiContext, query: &str) -> Reply {
+    let limit = match query_parameter(query, "limit").map(|limit| limit.parse::<usize>()) {
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => return error(400, "limit must be a number"),
+        None => DEFAULT_BUILD_LIMIT,
+    };
+    let job_names: Vec<String> = match query_parameter(query, "job") {
+        Some(job_name) if !is_safe_job_name(&job_name) => {
+            return error(400, &format!("invalid job name {}", job_name))
+        }
+        Some(job_name) => vec![job_name],
+        None => context
+            .jobs
+            .read()
+            .unwrap()
+            .iter()
+            .map(|job| job.name.clone())
+            .collect(),
+    };
+    let build_dir = context.settings.read().unwrap().build_dir.clone();
+    let mut builds: Vec<BuildInfo> = job_names
+        .iter()
+        .flat_map(|job_name| build::list_builds(&build_dir, job_name, limit))
+        .collect();
+    builds.sort_by(|build, other_build| {
+        (other_build.start_time, other_build.number).cmp(&(build.start_time, build.number))
+    });
+    builds.truncate(limit);
+    let builds: Vec<Value> = builds
+        .iter()
+        .map(|build| build_json(build, false))
+        .collect();
+    (200, json!({ "builds": builds }))
+}
+
+fn get_build(context: &ApiContext, build_id: &str) -> Reply {
+    match read_build(context, build_id) {
+        Ok(build) => (200, build_json(&build, true)),
+        Err(reply) => reply,
+    }
+}
+
+fn cancel_build(context: &ApiContext, build_id: &str) -> Reply {
+    let build = match read_build(context, build_id) {
+        Ok(build) => build,
+        Err(reply) => return reply,
+    };
+    let (reply_notifier, reply_listener) = bounded(1);
+    let request = OrchestratorRequest::CancelBuild {
+        build_id: build.id(),
+        reply: reply_notifier,
+    };
+    if context.request_notifier.send(request).is_err() {
+        return error(503, "the orchestrator is not running");
+    }
+    match reply_listener.recv_timeout(REQUEST_TIMEOUT) {
+        Ok(true) => (202, json!({ "build": build.id(), "cancelling": true })),
+        Ok(false) => error(409, &format!("build {} is not running", build.id())),
+        Err(_) => error(503, "the orchestrator did not answer"),
+    }
+}
+
+fn cancel_waiting_builds(context: &ApiContext, job_name: &str) -> Reply {
+    if find_job(&context.jobs, job_name).is_none() {
+        return error(404, &format!("unknown job {}", job_name));
+    }
+    let (reply_notifier, reply_listener) = bounded(1);
+    let request = OrchestratorRequest::CancelWaitingBuilds {
+        job_name: job_name.to_string(),
+        reply: reply_notifier,
+    };
+    if context.request_notifier.send(request).is_err() {
+        return error(503, "the orchestrator is not running");
+    }
+    match reply_listener.recv_timeout(REQUEST_TIMEOUT) {
+        Ok(0) => error(409, &format!("no build of {} is waiting", job_name)),
+        Ok(cancelled) => (200, json!({ "job": job_name, "cancelled": cancelled })),
+        Err(_) => error(503, "the orchestrator did not answer"),
+    }
+}
+
+/// Sends the log of the build as it is written, until the build finishes.
+fn follow_log(context: &ApiContext, request: Request, build_id: &str, query: &str) {
+    let build_dir = context.settings.read().unwrap().build_dir.clone();
+    let build = match read_build(context, build_id) {
+        Ok(build) => build,
+        Err(reply) => return respond(request, reply),
+    };
+    let log_name = match log_name(query) {
+        Some(log_name) => log_name,
+        None => return respond(request, error(400, "stream must be stdout or stderr")),
+    };
+    let mut log = match File::open(build.dir(&build_dir).join(log_name)) {
+        Ok(log) => log,
+        Err(_) => return respond(request, error(404, "the build has no log yet")),
+    };
+    let followed =
+        ChunkedWriter::start(request, "text/plain; charset=utf-8").and_then(|mut writer| {
+            build::follow_log(&mut log, &build_dir, &build, |data| {
+                writer.write_chunk(data)
+            })?;
+            writer.finish()
+        });
+    if let Err(write_err) = followed {
+        debug!("Stopped following the log of {}: {}", build.id(), write_err);
+    }
+}
+
+/// The log file picked by the `stream` query parameter, the standard output by default.
+fn log_name(query: &str) -> Option<&'static str> {
+    match query_parameter(query, "stream").as_deref() {
+        None | Some("stdout") => Some(build::STDOUT_LOG),
+        Some("stderr") => Some(build::STDERR_LOG),
+        Some(_) => None,
+    }
+}
+
+/// A response sent in chunks as soon as they are written. It is written by hand, since
+/// tiny_http holds back chunked responses until it has a few kilobytes to send.
+struct ChunkedWriter {
+    writer: Box<dyn Write + Send>,
+}
+
+impl ChunkedWriter {
+    fn start(request: Request, content_type: &str) -> io::Result<Self> {
+        let mut writer = request.into_writer();
+        write!(
+            writer,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nX-Content-Type-Options: nosniff\r\nTransfer-Encoding: chunked\r\n\r\n",
+            content_type
+        )?;
+        writer.flush()?;
+        Ok(ChunkedWriter { writer })
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        // an empty chunk would end the response
+        if data.is_empty() {
+            return Ok(());
+        }
+        write!(self.writer, "{:x}\r\n", data.len())?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(b"\r\n")?;
+        self.writer.flush()
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.writer.write_all(b"0\r\n\r\n")?;
+        self.writer.flush()
+    }
+}
+
+/// Whether the body of the request is declared as JSON, whatever its charset.
+fn has_json_content_type(request: &Request) -> bool {
+    request.headers().iter().any(|header| {
+        header.field.equiv("Content-Type")
+            && header
+                .value
+                .as_str()
+                .split(';')
+                .next()
+                .is_some_and(|media_type| {
+                    media_type.trim().eq_ignore_ascii_case("application/json")
+                })
+    })
+}
+
+fn read_body(request: &mut Request) -> Result<Vec<u8>, Reply> {
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(MAX_BODY_SIZE + 1)
+        .read_to_end(&mut body)
+        .map_err(|read_err| error(400, &format!("could not read the body: {}", read_err)))?;
+    if body.len() as u64 > MAX_BODY_SIZE {
+        return Err(error(413, "the body is too large"));
+    }
+    Ok(body)
+}
+
+/// Reads the metadata of the build with the given id (e.g. `backend/unit_tests/12`).
+fn read_build(context: &ApiContext, build_id: &str) -> Result<BuildInfo, Reply> {
+    let (job_name, number) = match build_id.rsplit_once('/') {
+        Some((job_name, number)) if is_safe_job_name(job_name) => (job_name, number),
+        _ => return Err(error(404, &format!("unknown build {}", build_id))),
+    };
+    let number = number
+        .parse()
+        .map_err(|_| error(404, &format!("unknown build {}", build_id)))?;
+    let build_dir = context.settings.read().unwrap().build_dir.clone();
+    BuildInfo::read(&build_dir, job_name, number)
+        .map_err(|_| error(404, &format!("unknown build {}", build_id)))
+}
+
+fn build_json(build: &BuildInfo, with_steps: bool) -> Value {
+    let mut value = json!({
+        "id": build.id(),
+        "job": build.job_name,
+        "number": build.number,
+        "trigger": build.trigger_source,
+        "status": build.status,
+        "infrastructure_error": build.infrastructure_error,
+        "retried": build.retried,
+        "start_time": build.start_time,
+        "end_time": build.end_time,
+        "duration": build.duration_secs(),
+    });
+    if with_steps {
+        value["steps"] = build
+            .steps
+            .iter()
+            .map(|step| {
+                json!({
+                    "name": step.name,
+                    "status": step.status,
+                    "duration": step.duration_secs,
+                })
+            })
+            .collect();
+    }
+    value
+}
+
+fn error(status: u16, message: &str) -> Reply {
+    (status, json!({ "error": message }))
+}
+
+fn respond_html(request: Request, (status, page): (u16, String)) {
+    let response = Response::from_string(page)
+        .with_status_code(status)
+        .with_header(
+            Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap(),
+        );
+    if let Err(respond_err) = request.respond(response) {
+        debug!("Failed to send a dashboard page: {}", respond_err);
+    }
+}
+
+fn respond(request: Request, (status, body): Reply) {
+    let response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap());
+    if let Err(respond_err) = request.respond(response) {
+        debug!("Failed to send an HTTP API response: {}", respond_err);
+    }
+}
+
+fn query_parameter(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| percent_decode(&value.replace('+', " ")))
+}
+
+fn percent_decode(encoded: &str) -> Option<String> {
+    let bytes = encoded.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%' {
+            let hex = encoded.get(index + 1..index + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
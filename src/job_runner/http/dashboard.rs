@@ -0,0 +1,336 @@
+//! A read-only dashboard, rendered on the server so that it needs no JavaScript:
+//!
+//! * `/`: the jobs with their last builds, and the status of the configuration updater.
+//! * `/ui/jobs/<job name>`: the last builds of a job.
+//! * `/ui/builds/<job name>/<number>?stream=stderr`: a build with its steps, and its standard
+//!   output (or error) log, followed until the build finishes.
+//!
+//! The overview pages refresh themselves every few seconds.
+
+use super::{is_safe_job_name, log_name, read_build, ApiContext, ChunkedWriter};
+use crate::job_runner::build::{self, BuildInfo};
+use crate::job_runner::UpdaterStatus;
+
+use chrono::{Local, TimeZone};
+use std::fmt::Write as _;
+use std::fs::File;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tiny_http::Request;
+
+/// How many builds of each job are shown on the overview.
+const OVERVIEW_BUILDS: usize = 10;
+/// How many builds are shown on the page of a job.
+const JOB_PAGE_BUILDS: usize = 50;
+const REFRESH_SECS: u32 = 5;
+const PAGE_TAIL: &str = "</body></html>";
+const STYLE: &str = "body{font-family:sans-serif;margin:2em;color:#222}\
+table{border-collapse:collapse;margin-bottom:1em}\
+th,td{text-align:left;padding:.2em .8em;border-bottom:1px solid #ddd}\
+pre{background:#f4f4f4;padding:1em;overflow-x:auto;white-space:pre-wrap}\
+a{color:#0645ad}.muted{color:#777}.SUCCESS{color:#1a7f37}.RUNNING{color:#0969da}\
+.FAILURE,.AGENT_FAILURE,.TIMED_OUT{color:#cf222e}.ABORTED,.CANCELLED{color:#9a6700}";
+
+/// A page of the dashboard, along with its status code.
+type Page = (u16, String);
+
+/// The jobs with their last builds, and the status of the configuration updater.
+pub fn overview(context: &ApiContext) -> Page {
+    let build_dir = context.settings.read().unwrap().build_dir.clone();
+    let updater_status = context.updater_status.read().unwrap().clone();
+    let mut body = String::new();
+    let _ = write!(
+        body,
+        "<h1>Formica CI</h1><p>{}</p>",
+        updater_summary(&updater_status)
+    );
+    let jobs = context.jobs.read().unwrap().clone();
+    for job in jobs.iter() {
+        let mut details = vec![format!(
+            "{} step{}",
+            job.steps.len(),
+            if job.steps.len() == 1 { "" } else { "s" }
+        )];
+        if !job.schedule.is_empty() {
+            let expressions: Vec<&str> = job
+                .schedule
+                .iter()
+                .map(|entry| entry.expression.as_str())
+                .collect();
+            details.push(format!("scheduled {}", escape(&expressions.join(", "))));
+        }
+        if job.poll_script.is_some() {
+            details.push("polled".to_string());
+        }
+        if let Some(pool) = &job.pool {
+            details.push(format!("pool {}", escape(&pool.name)));
+        }
+        let _ = write!(
+            body,
+            "<h2><a href=\"/ui/jobs/{}\">{}</a></h2><p class=\"muted\">{}</p>{}",
+            encode_path(&job.name),
+            escape(&job.name),
+            details.join(" · "),
+            builds_table(&build::list_builds(&build_dir, &job.name, OVERVIEW_BUILDS))
+        );
+    }
+    (200, page("Formica CI", Some(REFRESH_SECS), &body))
+}
+
+/// The last builds of a job.
+pub fn job_page(context: &ApiContext, job_name: &str) -> Page {
+    if !is_safe_job_name(job_name) {
+        return not_found(&format!("There is no job {}.", job_name));
+    }
+    let build_dir = context.settings.read().unwrap().build_dir.clone();
+    let builds = build::list_builds(&build_dir, job_name, JOB_PAGE_BUILDS);
+    let job_exists = context
+        .jobs
+        .read()
+        .unwrap()
+        .iter()
+        .any(|job| job.name == job_name);
+    if !job_exists && builds.is_empty() {
+        return not_found(&format!("There is no job {}.", job_name));
+    }
+    let body = format!(
+        "<p><a href=\"/\">Formica CI</a></p><h1>{}</h1>{}",
+        escape(job_name),
+        builds_table(&builds)
+    );
+    (200, page(job_name, Some(REFRESH_SECS), &body))
+}
+
+/// A build with its steps, followed by its log, which is sent as it grows.
+pub fn build_page(context: &ApiContext, request: Request, build_id: &str, query: &str) {
+    let build = match read_build(context, build_id) {
+        Ok(build) => build,
+        Err(_) => {
+            return super::respond_html(
+                request,
+                not_found(&format!("There is no build {}.", build_id)),
+            )
+        }
+    };
+    let log_name = log_name(query).unwrap_or(build::STDOUT_LOG);
+    let build_dir = context.settings.read().unwrap().build_dir.clone();
+    let mut body = format!(
+        "<p><a href=\"/\">Formica CI</a> / <a href=\"/ui/jobs/{}\">{}</a></p><h1>Build {}</h1>\
+         <table><tr><th>Status</th><td class=\"{status}\">{status}</td></tr>\
+         <tr><th>Trigger</th><td>{}</td></tr><tr><th>Started</th><td>{}</td></tr>\
+         <tr><th>Duration</th><td>{}</td></tr></table>",
+        encode_path(&build.job_name),
+        escape(&build.job_name),
+        escape(&build.id()),
+        escape(&build.trigger_source),
+        format_time(build.start_time),
+        format_duration(build.duration_secs()),
+        status = escape(&build.status),
+    );
+    if let Some(infrastructure_error) = &build.infrastructure_error {
+        let _ = write!(
+            body,
+            "<p class=\"AGENT_FAILURE\">Infrastructure error: {}</p>",
+            escape(infrastructure_error)
+        );
+    }
+    if !build.steps.is_empty() {
+        body.push_str("<table><tr><th>Step</th><th>Status</th><th>Duration</th></tr>");
+        for step in build.steps.iter() {
+            let _ = write!(
+                body,
+                "<tr><td>{}</td><td class=\"{status}\">{status}</td><td>{:.1}s</td></tr>",
+                escape(&step.name),
+                step.duration_secs,
+                status = escape(&step.status),
+            );
+        }
+        body.push_str("</table>");
+    }
+    let build_link = format!("/ui/builds/{}", encode_path(&build.id()));
+    let _ = write!(
+        body,
+        "<p><a href=\"{link}\">Output</a> · <a href=\"{link}?stream=stderr\">Errors</a></p>",
+        link = build_link
+    );
+    let mut log = match File::open(build.dir(&build_dir).join(log_name)) {
+        Ok(log) => log,
+        Err(_) => {
+            body.push_str("<p class=\"muted\">This build has no log.</p>");
+            return super::respond_html(request, (200, page(&build.id(), None, &body)));
+        }
+    };
+    if build.is_running() {
+        body.push_str("<p class=\"muted\">The log below follows the build until it finishes.</p>");
+    }
+    let head = format!("{}{}<pre>", page_head(&build.id(), None), body);
+    let followed =
+        ChunkedWriter::start(request, "text/html; charset=utf-8").and_then(|mut writer| {
+            writer.write_chunk(head.as_bytes())?;
+            build::follow_log(&mut log, &build_dir, &build, |data| {
+                writer.write_chunk(&escape_bytes(data))
+            })?;
+            if build.is_running() {
+                writer.write_chunk(finished_message(&build_dir, &build).as_bytes())?;
+            }
+            writer.write_chunk(format!("</pre>{}", PAGE_TAIL).as_bytes())?;
+            writer.finish()
+        });
+    if let Err(write_err) = followed {
+        debug!("Stopped following the log of {}: {}", build.id(), write_err);
+    }
+}
+
+/// Says how the build ended, once its log has been followed to the end.
+fn finished_message(build_dir: &Path, build: &BuildInfo) -> String {
+    match BuildInfo::read(build_dir, &build.job_name, build.number) {
+        Ok(build) => format!(
+            "\n<b>Build {} finished with status <span class=\"{status}\">{status}</span> (reload \
+             the page to see its steps)</b>",
+            escape(&build.id()),
+            status = escape(&build.status),
+        ),
+        Err(_) => String::new(),
+    }
+}
+
+fn builds_table(builds: &[BuildInfo]) -> String {
+    if builds.is_empty() {
+        return "<p class=\"muted\">No builds yet.</p>".to_string();
+    }
+    let mut table = String::from(
+        "<table><tr><th>Build</th><th>Status</th><th>Trigger</th><th>Started</th>\
+         <th>Duration</th></tr>",
+    );
+    for build in builds {
+        let _ = write!(
+            table,
+            "<tr><td><a href=\"/ui/builds/{}\">#{}</a></td><td class=\"{status}\">{status}</td>\
+             <td>{}</td><td>{}</td><td>{}</td></tr>",
+            encode_path(&build.id()),
+            build.number,
+            escape(&build.trigger_source),
+            format_time(build.start_time),
+            format_duration(build.duration_secs()),
+            status = escape(&build.status),
+        );
+    }
+    table.push_str("</table>");
+    table
+}
+
+fn updater_summary(status: &UpdaterStatus) -> String {
+    let last_run = status
+        .last_run
+        .map(|last_run| format!(", last run {}", format_system_time(last_run)))
+        .unwrap_or_default();
+    if status.running {
+        format!("The configuration is being updated{}.", last_run)
+    } else if let Some(last_error) = &status.last_error {
+        let last_success = status
+            .last_success
+            .map(|last_success| {
+                format!(
+                    " The last successful update was {}.",
+                    format_system_time(last_success)
+                )
+            })
+            .unwrap_or_default();
+        format!(
+            "<span class=\"FAILURE\">The last configuration update failed{}: {}.</span>{}",
+            last_run,
+            escape(last_error),
+            last_success
+        )
+    } else {
+        match status.last_success {
+            Some(last_success) => format!(
+                "The configuration was last updated {}.",
+                format_system_time(last_success)
+            ),
+            None => "The configuration has not been updated yet.".to_string(),
+        }
+    }
+}
+
+fn not_found(message: &str) -> Page {
+    let body = format!(
+        "<p><a href=\"/\">Formica CI</a></p><p>{}</p>",
+        escape(message)
+    );
+    (404, page("Not found", None, &body))
+}
+
+fn page(title: &str, refresh_secs: Option<u32>, body: &str) -> String {
+    format!("{}{}{}", page_head(title, refresh_secs), body, PAGE_TAIL)
+}
+
+fn page_head(title: &str, refresh_secs: Option<u32>) -> String {
+    let refresh = refresh_secs
+        .map(|secs| format!("<meta http-equiv=\"refresh\" content=\"{}\">", secs))
+        .unwrap_or_default();
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">{}<title>{}</title>\
+         <style>{}</style></head><body>",
+        refresh,
+        escape(title),
+        STYLE
+    )
+}
+
+fn format_time(unix_secs: u64) -> String {
+    match Local.timestamp_opt(unix_secs as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => "?".to_string(),
+    }
+}
+
+fn format_system_time(time: SystemTime) -> String {
+    format_time(
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    )
+}
+
+fn format_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn escape(text: &str) -> String {
+    String::from_utf8(escape_bytes(text.as_bytes())).unwrap()
+}
+
+/// Escapes HTML byte by byte, so that logs can be escaped in chunks that may split
+/// UTF-8 characters.
+fn escape_bytes(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data {
+        match byte {
+            b'&' => escaped.extend_from_slice(b"&amp;"),
+            b'<' => escaped.extend_from_slice(b"&lt;"),
+            b'>' => escaped.extend_from_slice(b"&gt;"),
+            b'"' => escaped.extend_from_slice(b"&quot;"),
+            b'\'' => escaped.extend_from_slice(b"&#39;"),
+            _ => escaped.push(*byte),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes a path for a link, keeping its slashes.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~/".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", byte);
+        }
+    }
+    encoded
+}
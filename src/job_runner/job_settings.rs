@@ -0,0 +1,172 @@
+//! The settings of a single job, read from the optional `job.toml` file of its folder:
+//!
+//! ```toml
+//! max_concurrent_builds = 1  # e.g. for deploy jobs (no limit by default)
+//! priority = 10              # builds of higher priority jobs get free slots first (0 by default)
+//! coalesce = true            # a new trigger replaces the one already waiting for a slot
+//! pool = "linux-large"       # run on an agent of the pool, instead of with an `agent_init` script
+//! timeout_secs = 3600        # builds running longer than this are stopped (no timeout by default)
+//! step_timeout_secs = 600    # same for each step
+//! retries = 2                # how many times a build is run again after an infrastructure failure
+//! retry_delay_secs = 30      # how long to wait before the first retry, doubled for each next one
+//! ```
+
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Name of the settings file, in the folder of a job.
+pub const JOB_SETTINGS_FILE: &str = "job.toml";
+/// How long to wait before the first retry of a build, unless the job says otherwise.
+const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct JobSettings {
+    /// How many builds of the job can run at the same time, or `None` for no limit.
+    pub max_concurrent_builds: Option<usize>,
+    /// Builds waiting for a free slot are started by decreasing priority, then in order
+    /// of arrival.
+    pub priority: i32,
+    /// Whether a trigger received while a build of the job is waiting for a free slot
+    /// replaces the trigger of that build, instead of queueing another build.
+    pub coalesce: bool,
+    /// The agent pool the builds of the job run on, for jobs without an `agent_init` script.
+    pub pool: Option<String>,
+    /// How long a build can run before it is stopped and recorded as `TIMED_OUT`.
+    pub timeout: Option<Duration>,
+    /// How long each step can run before the build is stopped and recorded as `TIMED_OUT`.
+    pub step_timeout: Option<Duration>,
+    /// How many times a build that failed because of its agent (rather than of its steps)
+    /// is run again.
+    pub retries: u32,
+    /// How long to wait before the first retry of a build. The delay doubles with every
+    /// retry of the same build.
+    pub retry_delay: Duration,
+}
+
+impl Default for JobSettings {
+    fn default() -> Self {
+        JobSettings {
+            max_concurrent_builds: None,
+            priority: 0,
+            coalesce: false,
+            pool: None,
+            timeout: None,
+            step_timeout: None,
+            retries: 0,
+            retry_delay: DEFAULT_RETRY_DELAY,
+        }
+    }
+}
+
+/// The settings file as written by the user, before validation.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobSettingsFile {
+    max_concurrent_builds: Option<usize>,
+    priority: Option<i32>,
+    coalesce: Option<bool>,
+    pool: Option<String>,
+    timeout_secs: Option<u64>,
+    step_timeout_secs: Option<u64>,
+    retries: Option<u32>,
+    retry_delay_secs: Option<u64>,
+}
+
+impl JobSettings {
+    /// Reads the settings file of a job folder, falling back to the default settings
+    /// when there is no such file.
+    pub fn load(job_folder: &Path) -> Result<JobSettings, JobSettingsError> {
+        let contents = match fs::read_to_string(job_folder.join(JOB_SETTINGS_FILE)) {
+            Ok(contents) => contents,
+            Err(read_err) if read_err.kind() == io::ErrorKind::NotFound => {
+                return Ok(JobSettings::default())
+            }
+            Err(read_err) => {
+                return Err(JobSettingsError {
+                    kind: JobSettingsErrorKind::Unreadable(read_err),
+                })
+            }
+        };
+        let settings_file: JobSettingsFile =
+            toml::from_str(&contents).map_err(|parse_err| JobSettingsError {
+                kind: JobSettingsErrorKind::InvalidSyntax(parse_err),
+            })?;
+        let defaults = JobSettings::default();
+        Ok(JobSettings {
+            max_concurrent_builds: match settings_file.max_concurrent_builds {
+                Some(0) => {
+                    return Err(invalid_value("max_concurrent_builds", "must be at least 1"))
+                }
+                max_concurrent_builds => max_concurrent_builds,
+            },
+            priority: settings_file.priority.unwrap_or(defaults.priority),
+            coalesce: settings_file.coalesce.unwrap_or(defaults.coalesce),
+            pool: match settings_file.pool {
+                Some(pool) if pool.trim().is_empty() => {
+                    return Err(invalid_value("pool", "cannot be empty"))
+                }
+                pool => pool,
+            },
+            timeout: optional_duration("timeout_secs", settings_file.timeout_secs)?,
+            step_timeout: optional_duration("step_timeout_secs", settings_file.step_timeout_secs)?,
+            retries: settings_file.retries.unwrap_or(defaults.retries),
+            retry_delay: optional_duration("retry_delay_secs", settings_file.retry_delay_secs)?
+                .unwrap_or(defaults.retry_delay),
+        })
+    }
+}
+
+fn optional_duration(
+    setting: &'static str,
+    seconds: Option<u64>,
+) -> Result<Option<Duration>, JobSettingsError> {
+    match seconds {
+        Some(0) => Err(invalid_value(setting, "must be at least 1 second")),
+        seconds => Ok(seconds.map(Duration::from_secs)),
+    }
+}
+
+fn invalid_value(setting: &'static str, reason: &'static str) -> JobSettingsError {
+    JobSettingsError {
+        kind: JobSettingsErrorKind::InvalidValue { setting, reason },
+    }
+}
+
+#[derive(Debug)]
+pub struct JobSettingsError {
+    pub kind: JobSettingsErrorKind,
+}
+
+#[derive(Debug)]
+pub enum JobSettingsErrorKind {
+    Unreadable(io::Error),
+    InvalidSyntax(toml::de::Error),
+    InvalidValue {
+        setting: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for JobSettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            JobSettingsErrorKind::Unreadable(read_err) => {
+                write!(f, "could not read {}: {}", JOB_SETTINGS_FILE, read_err)
+            }
+            JobSettingsErrorKind::InvalidSyntax(parse_err) => {
+                write!(f, "invalid {}: {}", JOB_SETTINGS_FILE, parse_err)
+            }
+            JobSettingsErrorKind::InvalidValue { setting, reason } => {
+                write!(
+                    f,
+                    "invalid {} in {}: {}",
+                    setting, JOB_SETTINGS_FILE, reason
+                )
+            }
+        }
+    }
+}
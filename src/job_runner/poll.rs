@@ -0,0 +1,176 @@
+//! Builds triggered by changes in source control, detected by the optional `poll` script
+//! of a job.
+//!
+//! The script is run in the job folder at the interval given by the settings, and prints
+//! the identifier of the latest revision (e.g. a commit hash) as the last line of its
+//! output. Whenever that identifier changes, a build of the revision is queued.
+
+use super::queue::JobTrigger;
+use super::script;
+use super::settings::SharedSettings;
+use super::{request_build, Job, OrchestratorRequest, SharedJobs};
+
+use crossbeam_channel::{unbounded, Sender};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const POLL: &str = "poll";
+/// File of the build folder of a job holding the last revision printed by its `poll` script.
+const LAST_REVISION: &str = ".last_revision";
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Runs the `poll` scripts of the jobs, and asks the orchestrator to queue a build whenever
+/// one of them prints a different revision than the last one recorded for the job. The
+/// revision is only recorded once the orchestrator accepted the build, so that a revision
+/// refused during a shutdown is built after the next start.
+///
+/// Each script runs in its own thread, so a slow one does not hold back the others, and
+/// a job is not polled again while its previous poll is still running. The first revision
+/// of a job that was never polled before is built as well.
+pub fn launch_revision_poller(
+    settings: SharedSettings,
+    jobs: SharedJobs,
+    request_notifier: Sender<OrchestratorRequest>,
+) {
+    thread::spawn(move || {
+        // the revision printed by the poll script of each job, once it has run
+        let (poll_result_sender, poll_results) = unbounded::<(String, Option<String>)>();
+        let mut last_polls: HashMap<String, Instant> = HashMap::new();
+        let mut running_polls: HashSet<String> = HashSet::new();
+        loop {
+            let (build_dir, poll_interval) = {
+                let settings = settings.read().unwrap();
+                (settings.build_dir.clone(), settings.poll_interval)
+            };
+            for (job_name, revision) in poll_results.try_iter() {
+                running_polls.remove(&job_name);
+                let revision = match revision {
+                    Some(revision) => revision,
+                    None => continue,
+                };
+                let state_file = build_dir.join(&job_name).join(LAST_REVISION);
+                if read_last_revision(&state_file).as_ref() == Some(&revision) {
+                    continue;
+                }
+                info!(
+                    "New revision {} of {}, queueing a build",
+                    revision, job_name
+                );
+                let trigger = JobTrigger::new(
+                    &job_name,
+                    format!("poll:{}", revision),
+                    Some(revision.clone()),
+                );
+                match request_build(&request_notifier, trigger) {
+                    Some(true) => write_last_revision(&state_file, &revision),
+                    Some(false) => {
+                        debug!("Shutdown in progress, stopping the revision poller");
+                        return;
+                    }
+                    None => {
+                        debug!("Orchestrator is gone, stopping the revision poller");
+                        return;
+                    }
+                }
+            }
+            let polled_jobs: Vec<Arc<Job>> = jobs
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|job| job.poll_script.is_some())
+                .cloned()
+                .collect();
+            last_polls.retain(|job_name, _| polled_jobs.iter().any(|job| &job.name == job_name));
+            for job in polled_jobs {
+                let poll_due = match last_polls.get(&job.name) {
+                    Some(last_poll) => last_poll.elapsed() >= poll_interval,
+                    None => true,
+                };
+                if !poll_due || running_polls.contains(&job.name) {
+                    continue;
+                }
+                last_polls.insert(job.name.clone(), Instant::now());
+                running_polls.insert(job.name.clone());
+                let last_revision =
+                    read_last_revision(&build_dir.join(&job.name).join(LAST_REVISION));
+                let poll_result_sender = poll_result_sender.clone();
+                thread::spawn(move || {
+                    let revision = run_poll_script(&job, last_revision);
+                    let _ = poll_result_sender.send((job.name.clone(), revision));
+                });
+            }
+            thread::sleep(CHECK_INTERVAL);
+        }
+    });
+}
+
+/// Runs the `poll` script of the job, returning the revision it printed, if any.
+fn run_poll_script(job: &Job, last_revision: Option<String>) -> Option<String> {
+    let poll_script = job.poll_script.as_ref()?;
+    let mut environment = BTreeMap::new();
+    environment.insert("FORMICA_JOB_NAME".to_string(), job.name.clone());
+    if let Some(last_revision) = last_revision {
+        environment.insert("FORMICA_LAST_REVISION".to_string(), last_revision);
+    }
+    let output = match script::execute_script_with_environment(
+        &job.root_folder,
+        poll_script,
+        &environment,
+    ) {
+        Ok(output) => output,
+        Err(execution_err) => {
+            warn!(
+                "The poll script of {} could not be run: {}",
+                job.name, execution_err
+            );
+            return None;
+        }
+    };
+    if !output.status.success() {
+        warn!(
+            "The poll script of {} failed with {}: {}",
+            job.name,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        return None;
+    }
+    let revision = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .map(str::to_string);
+    if revision.is_none() {
+        debug!("The poll script of {} printed no revision", job.name);
+    }
+    revision
+}
+
+fn read_last_revision(state_file: &Path) -> Option<String> {
+    match fs::read_to_string(state_file) {
+        Ok(revision) => Some(revision.trim().to_string()),
+        Err(read_err) => {
+            if read_err.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to read {}: {}", state_file.display(), read_err);
+            }
+            None
+        }
+    }
+}
+
+fn write_last_revision(state_file: &Path, revision: &str) {
+    let written = fs::create_dir_all(state_file.parent().unwrap())
+        .and_then(|_| fs::write(state_file, revision));
+    if let Err(write_err) = written {
+        warn!(
+            "Failed to record the last revision in {}: {}",
+            state_file.display(),
+            write_err
+        );
+    }
+}
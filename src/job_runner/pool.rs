@@ -0,0 +1,153 @@
+//! Agent pools, shared by the jobs that ask for them with the `pool` job setting instead of
+//! having their own `agent_init` script.
+//!
+//! Each folder of `formica_conf/pools` is a pool named after the folder, holding the
+//! `agent_init` (and optionally `agent_cleanup`) script of its agents, and an optional
+//! `pool.toml` file:
+//!
+//! ```toml
+//! capacity = 4  # how many builds can use the pool at the same time (1 by default)
+//! ```
+//!
+//! Every build running on a pool gets one of its slots, numbered from 0, so that the
+//! `agent_init` script knows which agent (e.g. VM or container) to use.
+
+use super::script;
+use super::AGENT_INIT;
+
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Folder of the configuration directory holding the pools, rather than jobs.
+pub const POOLS_DIR: &str = "pools";
+/// Name of the settings file, in the folder of a pool.
+pub const POOL_SETTINGS_FILE: &str = "pool.toml";
+
+pub struct Pool {
+    /// The name of the pool folder, e.g. `linux-large`.
+    pub name: String,
+    pub root_folder: PathBuf,
+    /// How many builds can use the pool at the same time.
+    pub capacity: usize,
+}
+
+/// The settings file as written by the user, before validation.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PoolSettingsFile {
+    capacity: Option<usize>,
+}
+
+impl Pool {
+    fn load(name: String, root_folder: PathBuf) -> Result<Pool, PoolError> {
+        match script::find_optional_script(&root_folder, AGENT_INIT) {
+            Ok(Some(_)) => (),
+            Ok(None) => {
+                return Err(PoolError {
+                    kind: PoolErrorKind::NoAgentInit,
+                })
+            }
+            Err(_) => {
+                return Err(PoolError {
+                    kind: PoolErrorKind::TooManyAgentInits,
+                })
+            }
+        }
+        let contents = match fs::read_to_string(root_folder.join(POOL_SETTINGS_FILE)) {
+            Ok(contents) => contents,
+            Err(read_err) if read_err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(read_err) => {
+                return Err(PoolError {
+                    kind: PoolErrorKind::Unreadable(read_err),
+                })
+            }
+        };
+        let settings_file: PoolSettingsFile =
+            toml::from_str(&contents).map_err(|parse_err| PoolError {
+                kind: PoolErrorKind::InvalidSyntax(parse_err),
+            })?;
+        let capacity = match settings_file.capacity {
+            Some(0) => {
+                return Err(PoolError {
+                    kind: PoolErrorKind::InvalidCapacity,
+                })
+            }
+            capacity => capacity.unwrap_or(1),
+        };
+        Ok(Pool {
+            name,
+            root_folder,
+            capacity,
+        })
+    }
+}
+
+/// Reads the pools of the configuration directory. Invalid pools are logged and ignored.
+pub fn find_pools(config_dir: &Path) -> Vec<Pool> {
+    let pool_folders = match fs::read_dir(config_dir.join(POOLS_DIR)) {
+        Ok(pool_folders) => pool_folders,
+        Err(read_err) if read_err.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(read_err) => {
+            warn!("Failed to list the agent pools: {}", read_err);
+            return Vec::new();
+        }
+    };
+    let mut pools: Vec<Pool> = pool_folders
+        .filter_map(|folder| folder.ok())
+        .filter(|folder| folder.path().is_dir())
+        .filter_map(|folder| {
+            let name = folder.file_name().into_string().ok()?;
+            if name.starts_with('.') {
+                return None;
+            }
+            match Pool::load(name.clone(), folder.path()) {
+                Ok(pool) => Some(pool),
+                Err(pool_err) => {
+                    error!("Ignoring agent pool {}: {}", name, pool_err);
+                    None
+                }
+            }
+        })
+        .collect();
+    pools.sort_by(|pool, other_pool| pool.name.cmp(&other_pool.name));
+    pools
+}
+
+#[derive(Debug)]
+pub struct PoolError {
+    pub kind: PoolErrorKind,
+}
+
+#[derive(Debug)]
+pub enum PoolErrorKind {
+    NoAgentInit,
+    TooManyAgentInits,
+    Unreadable(io::Error),
+    InvalidSyntax(toml::de::Error),
+    InvalidCapacity,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            PoolErrorKind::NoAgentInit => write!(f, "it has no {} script", AGENT_INIT),
+            PoolErrorKind::TooManyAgentInits => {
+                write!(f, "it has more than one {} script", AGENT_INIT)
+            }
+            PoolErrorKind::Unreadable(read_err) => {
+                write!(f, "could not read {}: {}", POOL_SETTINGS_FILE, read_err)
+            }
+            PoolErrorKind::InvalidSyntax(parse_err) => {
+                write!(f, "invalid {}: {}", POOL_SETTINGS_FILE, parse_err)
+            }
+            PoolErrorKind::InvalidCapacity => write!(
+                f,
+                "invalid capacity in {}: must be at least 1",
+                POOL_SETTINGS_FILE
+            ),
+        }
+    }
+}
@@ -0,0 +1,265 @@
+//! Scripts run once a build has finished, e.g. to send notifications:
+//!
+//! * `on_success`: the build succeeded.
+//! * `on_failure`: the build failed, because of its steps, of its agent or of a timeout, and
+//!   will not be retried.
+//! * `on_fixed`: the build succeeded, after the previous build of the job failed.
+//! * `on_always`: after every build, whatever its result.
+//!
+//! Each script is looked up in the folder of the job first, then at the root of the
+//! configuration directory, which holds the defaults of all the jobs. The scripts get the
+//! result of the build through environment variables, and their output goes to the
+//! `hooks.log` file of the build. The scripts run after the build has freed its slot, and
+//! are killed by an immediate shutdown.
+
+use super::build::{self, BuildRecord, BuildStatus};
+use super::queue::JobTrigger;
+use super::{
+    absolute_path, build_environment, script, BuildControl, Job, CONFIG, PROCESS_POLL_INTERVAL,
+};
+
+use crossbeam_channel::{after, never, select, Receiver};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ExitStatus};
+use std::time::Duration;
+
+pub const ON_SUCCESS: &str = "on_success";
+pub const ON_FAILURE: &str = "on_failure";
+pub const ON_FIXED: &str = "on_fixed";
+pub const ON_ALWAYS: &str = "on_always";
+/// How long a post-build script can run before it is killed.
+const POST_BUILD_TIMEOUT: Duration = Duration::from_secs(300);
+/// How many of the last builds of the job are searched for the result of the previous one.
+const PREVIOUS_BUILD_LIMIT: usize = 50;
+
+/// Runs the post-build scripts matching the result of the finished build, one after the other,
+/// until `interruptions` gets `Abort` or `Kill`.
+pub fn run_post_build_scripts(
+    job: &Job,
+    trigger: &JobTrigger,
+    build: &BuildRecord,
+    build_root: &Path,
+    interruptions: &Receiver<BuildControl>,
+) {
+    let previous_status = previous_status(build_root, build);
+    let mut script_names = Vec::new();
+    match build.status {
+        BuildStatus::Success => {
+            script_names.push(ON_SUCCESS);
+            let success = BuildStatus::Success.to_string();
+            if previous_status
+                .as_ref()
+                .is_some_and(|previous_status| *previous_status != success)
+            {
+                script_names.push(ON_FIXED);
+            }
+        }
+        BuildStatus::Failure | BuildStatus::AgentFailure | BuildStatus::TimedOut => {
+            // only the last attempt of a retried build counts
+            if !build.retried {
+                script_names.push(ON_FAILURE);
+            }
+        }
+        BuildStatus::Running | BuildStatus::Aborted | BuildStatus::Cancelled => (),
+    }
+    script_names.push(ON_ALWAYS);
+    let environment = post_build_environment(trigger, build, previous_status);
+    for script_name in script_names {
+        if let Some((script_folder, script_file)) =
+            find_post_build_script(&job.root_folder, script_name)
+        {
+            let script_end = run_post_build_script(
+                &script_folder,
+                &script_file,
+                script_name,
+                build,
+                &environment,
+                interruptions,
+            );
+            if let Ok(ScriptEnd::Stopped) = script_end {
+                break;
+            }
+        }
+    }
+}
+
+/// How a post-build script ended.
+enum ScriptEnd {
+    Exited(ExitStatus),
+    /// Killed after running past the timeout.
+    TimedOut,
+    /// Killed, or not even started, because of an `Abort` or `Kill`.
+    Stopped,
+}
+
+/// The status of the last build of the job that finished before this one, leaving out
+/// the builds that were stopped before they could succeed or fail, and the attempts that
+/// were retried.
+fn previous_status(build_root: &Path, build: &BuildRecord) -> Option<String> {
+    let unfinished_statuses = [
+        BuildStatus::Running.to_string(),
+        BuildStatus::Aborted.to_string(),
+        BuildStatus::Cancelled.to_string(),
+    ];
+    build::list_builds(build_root, &build.job_name, PREVIOUS_BUILD_LIMIT)
+        .into_iter()
+        .filter(|previous_build| previous_build.number < build.number && !previous_build.retried)
+        .map(|previous_build| previous_build.status)
+        .find(|status| !unfinished_statuses.contains(status))
+}
+
+/// The script of the job folder if there is one, or else the default one of the configuration
+/// directory, along with the folder it is in.
+fn find_post_build_script(job_folder: &Path, script_name: &str) -> Option<(PathBuf, String)> {
+    for script_folder in [job_folder.to_path_buf(), PathBuf::from(CONFIG)] {
+        match script::find_optional_script(&script_folder, script_name) {
+            Ok(Some(script_file)) => return Some((script_folder, script_file)),
+            Ok(None) => (),
+            Err(_) => {
+                warn!(
+                    "More than one {} script found in {}, not running it!",
+                    script_name,
+                    script_folder.display()
+                );
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// The build parameters and the variables of the `agent_init` script, along with the
+/// result of the build.
+fn post_build_environment(
+    trigger: &JobTrigger,
+    build: &BuildRecord,
+    previous_status: Option<String>,
+) -> BTreeMap<String, String> {
+    let mut environment = trigger.environment();
+    environment.extend(build_environment(build));
+    environment.insert(String::from("FORMICA_BUILD_ID"), build.id());
+    environment.insert(
+        String::from("FORMICA_BUILD_STATUS"),
+        build.status.to_string(),
+    );
+    if let Some(previous_status) = previous_status {
+        environment.insert(String::from("FORMICA_PREVIOUS_STATUS"), previous_status);
+    }
+    environment.insert(
+        String::from("FORMICA_TRIGGER"),
+        build.trigger_source.clone(),
+    );
+    let duration = build
+        .end_time
+        .and_then(|end_time| end_time.duration_since(build.start_time).ok())
+        .unwrap_or_default();
+    environment.insert(
+        String::from("FORMICA_DURATION_SECS"),
+        duration.as_secs().to_string(),
+    );
+    environment.insert(
+        String::from("FORMICA_STDOUT_LOG"),
+        absolute_path(build.dir.join(build::STDOUT_LOG)),
+    );
+    environment.insert(
+        String::from("FORMICA_STDERR_LOG"),
+        absolute_path(build.dir.join(build::STDERR_LOG)),
+    );
+    if let Some(infrastructure_error) = &build.infrastructure_error {
+        environment.insert(
+            String::from("FORMICA_INFRASTRUCTURE_ERROR"),
+            infrastructure_error.clone(),
+        );
+    }
+    if build.retried {
+        environment.insert(String::from("FORMICA_WILL_RETRY"), String::from("1"));
+    }
+    environment
+}
+
+/// Runs a post-build script, killing it (along with everything it started) if it runs past
+/// the timeout or the build gets `Abort` or `Kill`. Failures are logged, and do not change
+/// the result of the build.
+fn run_post_build_script(
+    script_folder: &PathBuf,
+    script_file: &str,
+    script_name: &str,
+    build: &BuildRecord,
+    environment: &BTreeMap<String, String>,
+    interruptions: &Receiver<BuildControl>,
+) -> io::Result<ScriptEnd> {
+    if interruptions.try_iter().any(is_stop) {
+        return Ok(ScriptEnd::Stopped);
+    }
+    info!("Running the {} script of build {}", script_name, build.id());
+    let script_end = build.open_hooks_log().and_then(|logs| {
+        let mut process =
+            script::spawn_logged_script(script_folder, script_file, environment, logs)?;
+        let script_end = wait_for_script(&mut process, interruptions);
+        script::release_process_group(&process);
+        script_end
+    });
+    match &script_end {
+        Ok(ScriptEnd::Exited(exit_status)) if exit_status.success() => (),
+        Ok(ScriptEnd::Exited(exit_status)) => warn!(
+            "The {} script of build {} failed with {}",
+            script_name,
+            build.id(),
+            exit_status
+        ),
+        Ok(ScriptEnd::TimedOut) => warn!(
+            "The {} script of build {} was killed after running for {}s",
+            script_name,
+            build.id(),
+            POST_BUILD_TIMEOUT.as_secs()
+        ),
+        Ok(ScriptEnd::Stopped) => warn!(
+            "The {} script of build {} was killed by the shutdown",
+            script_name,
+            build.id()
+        ),
+        Err(script_err) => warn!(
+            "The {} script of build {} could not be run: {}",
+            script_name,
+            build.id(),
+            script_err
+        ),
+    }
+    script_end
+}
+
+/// Waits for the script to exit, killing it along with everything it started once it runs
+/// past the timeout, or on `Abort` or `Kill`. Other build control messages are ignored, since
+/// the build is over.
+fn wait_for_script(
+    process: &mut Child,
+    interruptions: &Receiver<BuildControl>,
+) -> io::Result<ScriptEnd> {
+    let deadline = after(POST_BUILD_TIMEOUT);
+    let mut interruptions = interruptions.clone();
+    let script_end = loop {
+        // the script is only reaped once its process group has been killed, if need be
+        if script::has_exited(process)? {
+            return process.wait().map(ScriptEnd::Exited);
+        }
+        select! {
+            recv(interruptions) -> interruption => match interruption {
+                Ok(interruption) if is_stop(interruption) => break ScriptEnd::Stopped,
+                Ok(_) => (),
+                // nobody can stop the script anymore, except for the timeout
+                Err(_) => interruptions = never(),
+            },
+            recv(deadline) -> _ => break ScriptEnd::TimedOut,
+            default(PROCESS_POLL_INTERVAL) => (),
+        }
+    };
+    script::kill_process_tree(process)?;
+    process.wait()?;
+    Ok(script_end)
+}
+
+fn is_stop(interruption: BuildControl) -> bool {
+    matches!(interruption, BuildControl::Abort | BuildControl::Kill)
+}
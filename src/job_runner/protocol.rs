@@ -0,0 +1,473 @@
+//! The line-oriented protocol spoken between the orchestrator and the agent tracker
+//! process started by the `agent_init` script of a job.
+//!
+//! The orchestrator writes commands to the standard input of the agent, one per line:
+//!
+//! * `RUN <step name> <line count>`, followed by that many lines holding the step script.
+//!   The agent answers with any number of `OUT`/`ERR` lines and then a `STATUS` line.
+//!   Step names cannot contain whitespace.
+//! * `SET <NAME> <value>`: sets an environment variable for the steps run afterwards.
+//!   The agent answers `OK` (or `FAIL`). Values cannot contain line breaks.
+//! * `UPLOAD <path>`: asks the agent to send back a file (the path is relative to the
+//!   directory the steps run in). The agent answers with any number of `DATA` lines and
+//!   then `OK` (or `FAIL`).
+//! * `EXIT`: there are no more commands, the agent should clean up and exit.
+//!
+//! The agent writes its responses to its standard output, one per line:
+//!
+//! * `READY <protocol version>`: must be the first response, once the agent can take commands.
+//! * `OUT <text>` / `ERR <text>`: a line written by the running step to its stdout/stderr.
+//! * `STATUS <exit code>`: the running step has finished.
+//! * `DATA <base64>`: a chunk of a file being uploaded.
+//! * `OK`: the last `SET` or `UPLOAD` command succeeded.
+//! * `FAIL <message>`: the last command could not be carried out.
+//! * `LOG <text>`: a diagnostic message of the agent itself.
+//!
+//! Any other line is treated as a diagnostic message of the agent.
+
+use super::BuildControl;
+
+use crossbeam_channel::{never, select, unbounded, Receiver};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::thread;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq)]
+pub enum AgentMessage {
+    Ready(String),
+    Out(String),
+    Err(String),
+    Status(String),
+    Data(String),
+    Ok,
+    Fail(String),
+    Log(String),
+}
+
+impl AgentMessage {
+    fn parse(line: &str) -> AgentMessage {
+        let (keyword, argument) = match line.split_once(' ') {
+            Some((keyword, argument)) => (keyword, argument),
+            None => (line, ""),
+        };
+        let argument = argument.to_string();
+        match keyword {
+            "READY" => AgentMessage::Ready(argument),
+            "OUT" => AgentMessage::Out(argument),
+            "ERR" => AgentMessage::Err(argument),
+            "STATUS" => AgentMessage::Status(argument),
+            "DATA" => AgentMessage::Data(argument),
+            "OK" => AgentMessage::Ok,
+            "FAIL" => AgentMessage::Fail(argument),
+            "LOG" => AgentMessage::Log(argument),
+            _ => AgentMessage::Log(line.to_string()),
+        }
+    }
+}
+
+/// The stream a line of step output was written to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+/// The orchestrator side of a connection to an agent.
+pub struct AgentConnection<W: Write> {
+    input: W,
+    messages: Receiver<AgentMessage>,
+    job_name: String,
+    interruptions: Receiver<BuildControl>,
+    interruption: Option<BuildControl>,
+}
+
+impl<W: Write> AgentConnection<W> {
+    /// Wraps the standard input and output of an agent process. The output is parsed
+    /// from a separate thread, so that the agent never blocks on a full pipe.
+    ///
+    /// A message arriving through `interruptions` while waiting for the agent makes the
+    /// pending command (and any later one) fail, see [`AgentConnection::interruption`].
+    pub fn new(
+        input: W,
+        output: impl Read + Send + 'static,
+        job_name: &str,
+        interruptions: Receiver<BuildControl>,
+    ) -> Self {
+        let (sender, messages) = unbounded();
+        thread::spawn(move || {
+            for line in BufReader::new(output).lines() {
+                let message = match line {
+                    Ok(line) => AgentMessage::parse(&line),
+                    Err(_) => break,
+                };
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        AgentConnection {
+            input,
+            messages,
+            job_name: job_name.to_string(),
+            interruptions,
+            interruption: None,
+        }
+    }
+
+    /// The build control message that interrupted the connection, if any.
+    pub fn interruption(&self) -> Option<BuildControl> {
+        self.interruption
+    }
+
+    /// Waits for the agent to announce that it is ready, checking the protocol version.
+    pub fn handshake(&mut self) -> Result<(), ProtocolError> {
+        match self.next_message()? {
+            AgentMessage::Ready(version) => {
+                if version.trim() == PROTOCOL_VERSION.to_string() {
+                    Ok(())
+                } else {
+                    Err(ProtocolError {
+                        kind: ProtocolErrorKind::UnsupportedVersion(version),
+                    })
+                }
+            }
+            unexpected => Err(unexpected_message(unexpected)),
+        }
+    }
+
+    /// Sets an environment variable for the steps run after this command. The value
+    /// must fit on the line of the command.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ProtocolError> {
+        if value.contains(['\n', '\r']) {
+            return Err(ProtocolError {
+                kind: ProtocolErrorKind::MultilineValue(name.to_string()),
+            });
+        }
+        self.send(&format!("SET {} {}\n", name, value))?;
+        self.expect_ok()
+    }
+
+    /// Runs a step script on the agent, passing each line of its output to `on_output`,
+    /// and returns the exit code of the step. The step name must be a single word.
+    pub fn run(
+        &mut self,
+        step_name: &str,
+        step_script: &str,
+        mut on_output: impl FnMut(OutputStream, &str),
+    ) -> Result<i32, ProtocolError> {
+        if !is_valid_step_name(step_name) {
+            return Err(ProtocolError {
+                kind: ProtocolErrorKind::InvalidStepName(step_name.to_string()),
+            });
+        }
+        let script_lines: Vec<&str> = step_script.lines().collect();
+        let mut command = format!("RUN {} {}\n", step_name, script_lines.len());
+        for line in script_lines {
+            command.push_str(line);
+            command.push('\n');
+        }
+        self.send(&command)?;
+        loop {
+            match self.next_message()? {
+                AgentMessage::Out(line) => on_output(OutputStream::Stdout, &line),
+                AgentMessage::Err(line) => on_output(OutputStream::Stderr, &line),
+                AgentMessage::Status(exit_code) => {
+                    return exit_code.trim().parse().map_err(|_| ProtocolError {
+                        kind: ProtocolErrorKind::InvalidExitCode(exit_code),
+                    })
+                }
+                AgentMessage::Fail(reason) => {
+                    return Err(ProtocolError {
+                        kind: ProtocolErrorKind::CommandFailed(reason),
+                    })
+                }
+                unexpected => return Err(unexpected_message(unexpected)),
+            }
+        }
+    }
+
+    /// Asks the agent to send back the file at `path`, and returns its contents.
+    pub fn upload(&mut self, path: &str) -> Result<Vec<u8>, ProtocolError> {
+        self.send(&format!("UPLOAD {}\n", path))?;
+        let mut contents = Vec::new();
+        loop {
+            match self.next_message()? {
+                AgentMessage::Data(chunk) => {
+                    let decoded = base64::decode(chunk.trim()).map_err(|_| ProtocolError {
+                        kind: ProtocolErrorKind::InvalidData,
+                    })?;
+                    contents.extend(decoded);
+                }
+                AgentMessage::Ok => return Ok(contents),
+                AgentMessage::Fail(reason) => {
+                    return Err(ProtocolError {
+                        kind: ProtocolErrorKind::CommandFailed(reason),
+                    })
+                }
+                unexpected => return Err(unexpected_message(unexpected)),
+            }
+        }
+    }
+
+    /// Tells the agent that there are no more commands.
+    pub fn exit(mut self) -> Result<(), ProtocolError> {
+        self.send("EXIT\n")
+    }
+
+    fn send(&mut self, command: &str) -> Result<(), ProtocolError> {
+        self.input
+            .write_all(command.as_bytes())
+            .and_then(|_| self.input.flush())
+            .map_err(|write_err| ProtocolError {
+                kind: ProtocolErrorKind::Io(write_err),
+            })
+    }
+
+    fn expect_ok(&mut self) -> Result<(), ProtocolError> {
+        match self.next_message()? {
+            AgentMessage::Ok => Ok(()),
+            AgentMessage::Fail(reason) => Err(ProtocolError {
+                kind: ProtocolErrorKind::CommandFailed(reason),
+            }),
+            unexpected => Err(unexpected_message(unexpected)),
+        }
+    }
+
+    /// Returns the next message of the agent, logging its diagnostic messages on the way.
+    fn next_message(&mut self) -> Result<AgentMessage, ProtocolError> {
+        loop {
+            if self.interruption.is_some() {
+                return Err(ProtocolError {
+                    kind: ProtocolErrorKind::Interrupted,
+                });
+            }
+            select! {
+                recv(self.messages) -> message => match message {
+                    Ok(AgentMessage::Log(line)) => info!("[{} agent] {}", self.job_name, line),
+                    Ok(message) => return Ok(message),
+                    Err(_) => {
+                        return Err(ProtocolError {
+                            kind: ProtocolErrorKind::Disconnected,
+                        })
+                    }
+                },
+                recv(self.interruptions) -> interruption => match interruption {
+                    Ok(interruption) => self.interruption = Some(interruption),
+                    Err(_) => self.interruptions = never(),
+                },
+            }
+        }
+    }
+}
+
+/// Whether the step name fits in a `RUN` command, where it is followed by the line count.
+pub fn is_valid_step_name(step_name: &str) -> bool {
+    !step_name.is_empty() && !step_name.contains(char::is_whitespace)
+}
+
+fn unexpected_message(message: AgentMessage) -> ProtocolError {
+    ProtocolError {
+        kind: ProtocolErrorKind::UnexpectedMessage(format!("{:?}", message)),
+    }
+}
+
+#[derive(Debug)]
+pub struct ProtocolError {
+    pub kind: ProtocolErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ProtocolErrorKind {
+    /// The agent closed its output, normally because it exited.
+    Disconnected,
+    /// The orchestrator stopped waiting for the agent.
+    Interrupted,
+    Io(io::Error),
+    UnsupportedVersion(String),
+    UnexpectedMessage(String),
+    InvalidExitCode(String),
+    InvalidData,
+    CommandFailed(String),
+    /// The value of a `SET` command holds a line break.
+    MultilineValue(String),
+    /// The name of a `RUN` command is empty or holds whitespace.
+    InvalidStepName(String),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ProtocolErrorKind::Disconnected => write!(f, "the agent disconnected"),
+            ProtocolErrorKind::Interrupted => write!(f, "the build was interrupted"),
+            ProtocolErrorKind::Io(io_err) => write!(f, "failed to talk to the agent: {}", io_err),
+            ProtocolErrorKind::UnsupportedVersion(version) => write!(
+                f,
+                "the agent speaks protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+            ProtocolErrorKind::UnexpectedMessage(message) => {
+                write!(f, "unexpected message from the agent: {}", message)
+            }
+            ProtocolErrorKind::InvalidExitCode(exit_code) => {
+                write!(f, "invalid exit code from the agent: {}", exit_code)
+            }
+            ProtocolErrorKind::InvalidData => write!(f, "invalid base64 data from the agent"),
+            ProtocolErrorKind::CommandFailed(reason) => {
+                write!(f, "the agent failed the command: {}", reason)
+            }
+            ProtocolErrorKind::MultilineValue(name) => {
+                write!(f, "the value of {} cannot contain line breaks", name)
+            }
+            ProtocolErrorKind::InvalidStepName(step_name) => {
+                write!(f, "the step name '{}' cannot contain whitespace", step_name)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    /// A connection to an agent that answers with the given lines, whatever the commands.
+    fn connect(agent_output: &str) -> AgentConnection<Vec<u8>> {
+        AgentConnection::new(
+            Vec::new(),
+            Cursor::new(agent_output.as_bytes().to_vec()),
+            "job",
+            never(),
+        )
+    }
+
+    fn sent_commands(agent: &AgentConnection<Vec<u8>>) -> &str {
+        std::str::from_utf8(&agent.input).unwrap()
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(
+            AgentMessage::parse("READY 1"),
+            AgentMessage::Ready("1".into())
+        );
+        assert_eq!(
+            AgentMessage::parse("OUT  two  spaces "),
+            AgentMessage::Out(" two  spaces ".into())
+        );
+        assert_eq!(
+            AgentMessage::parse("ERR oops"),
+            AgentMessage::Err("oops".into())
+        );
+        assert_eq!(
+            AgentMessage::parse("STATUS 0"),
+            AgentMessage::Status("0".into())
+        );
+        assert_eq!(
+            AgentMessage::parse("DATA aGk="),
+            AgentMessage::Data("aGk=".into())
+        );
+        assert_eq!(AgentMessage::parse("OK"), AgentMessage::Ok);
+        assert_eq!(
+            AgentMessage::parse("FAIL no such file"),
+            AgentMessage::Fail("no such file".into())
+        );
+        assert_eq!(
+            AgentMessage::parse("LOG started"),
+            AgentMessage::Log("started".into())
+        );
+        assert_eq!(AgentMessage::parse("OUT"), AgentMessage::Out(String::new()));
+    }
+
+    #[test]
+    fn malformed_messages() {
+        assert_eq!(AgentMessage::parse(""), AgentMessage::Log(String::new()));
+        assert_eq!(AgentMessage::parse("ok"), AgentMessage::Log("ok".into()));
+        assert_eq!(
+            AgentMessage::parse("Starting the VM..."),
+            AgentMessage::Log("Starting the VM...".into())
+        );
+        assert_eq!(
+            AgentMessage::parse(" OUT leading space"),
+            AgentMessage::Log(" OUT leading space".into())
+        );
+    }
+
+    #[test]
+    fn exchange() {
+        let mut agent = connect(
+            "READY 1\nOK\nLOG diagnostic\nOUT building\nERR warning\nSTATUS 3\nDATA aGVsbG8=\nDATA IHdvcmxk\nOK\n",
+        );
+        agent.handshake().unwrap();
+        agent.set("VERSION", "1.2 beta").unwrap();
+        let mut output = Vec::new();
+        let exit_code = agent
+            .run("step_01_build", "make\nmake check\n", |stream, line| {
+                output.push((stream, line.to_string()))
+            })
+            .unwrap();
+        assert_eq!(exit_code, 3);
+        assert_eq!(
+            output,
+            [
+                (OutputStream::Stdout, String::from("building")),
+                (OutputStream::Stderr, String::from("warning"))
+            ]
+        );
+        assert_eq!(agent.upload("dist/report.txt").unwrap(), b"hello world");
+        assert_eq!(
+            sent_commands(&agent),
+            "SET VERSION 1.2 beta\nRUN step_01_build 2\nmake\nmake check\nUPLOAD dist/report.txt\n"
+        );
+    }
+
+    #[test]
+    fn commands_that_do_not_fit_on_a_line() {
+        let mut agent = connect("READY 1\n");
+        agent.handshake().unwrap();
+        assert!(matches!(
+            agent.set("NOTES", "first\nsecond"),
+            Err(ProtocolError {
+                kind: ProtocolErrorKind::MultilineValue(_)
+            })
+        ));
+        assert!(matches!(
+            agent.run("step 01", "true", |_, _| ()),
+            Err(ProtocolError {
+                kind: ProtocolErrorKind::InvalidStepName(_)
+            })
+        ));
+        assert_eq!(sent_commands(&agent), "");
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let kind = |result: Result<i32, ProtocolError>| result.unwrap_err().kind;
+        assert!(matches!(
+            connect("READY 2\n").handshake().unwrap_err().kind,
+            ProtocolErrorKind::UnsupportedVersion(_)
+        ));
+        assert!(matches!(
+            connect("OK\n").handshake().unwrap_err().kind,
+            ProtocolErrorKind::UnexpectedMessage(_)
+        ));
+        assert!(matches!(
+            kind(connect("STATUS x\n").run("step_01", "true", |_, _| ())),
+            ProtocolErrorKind::InvalidExitCode(_)
+        ));
+        assert!(matches!(
+            kind(connect("FAIL busy\n").run("step_01", "true", |_, _| ())),
+            ProtocolErrorKind::CommandFailed(_)
+        ));
+        assert!(matches!(
+            kind(connect("OUT partial\n").run("step_01", "true", |_, _| ())),
+            ProtocolErrorKind::Disconnected
+        ));
+        assert!(matches!(
+            connect("DATA !!!\n").upload("file").unwrap_err().kind,
+            ProtocolErrorKind::InvalidData
+        ));
+    }
+}
@@ -0,0 +1,537 @@
+use super::settings::SharedSettings;
+use super::{find_job, relative_name, OrchestratorRequest, SharedJobs};
+
+use crossbeam_channel::{bounded, Sender};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use walkdir::{DirEntry, WalkDir};
+
+/// Suffix given to trigger files once they have been claimed by the poller.
+const CLAIM_SUFFIX: &str = ".claimed";
+/// Suffix given to claimed trigger files whose contents could not be parsed.
+const REJECTED_SUFFIX: &str = ".rejected";
+/// Parameter holding the revision to build, rather than a plain build parameter.
+pub const REVISION_PARAMETER: &str = "REVISION";
+/// Prefix reserved for the variables set by Formica itself.
+pub const RESERVED_PARAMETER_PREFIX: &str = "FORMICA_";
+/// Everything after this character in a trigger file name is ignored, so that
+/// several builds of the same job can be requested at once (e.g. `my_job@1`, `my_job@2`).
+const TRIGGER_TAG_SEPARATOR: char = '@';
+/// Folder of the queue directory holding cancellation requests, rather than triggers:
+/// `cancel/<build id>` cancels a running build (e.g. `cancel/backend/unit_tests/12`), and
+/// `cancel/<job name>` removes the builds of the job waiting for a free slot. A request
+/// naming a job (e.g. `cancel/releases/2024`) is never taken for a build id.
+pub const CANCEL_DIR: &str = "cancel";
+/// How long to wait for the orchestrator to handle a cancellation request.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+static CLAIM_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A request to run a job, as received by the orchestrator.
+pub struct JobTrigger {
+    pub job_name: String,
+    /// Describes where the trigger came from, e.g. `queue:my_job@2`.
+    pub source: String,
+    pub parameters: BTreeMap<String, String>,
+    pub revision: Option<String>,
+    /// 1 for the first run of the build, 2 for its first retry, and so on.
+    pub attempt: u32,
+    claim: Option<PathBuf>,
+}
+
+impl JobTrigger {
+    /// A trigger without parameters, coming from somewhere else than the queue directory.
+    pub fn new(job_name: &str, source: String, revision: Option<String>) -> Self {
+        JobTrigger {
+            job_name: job_name.to_string(),
+            source,
+            parameters: BTreeMap::new(),
+            revision,
+            attempt: 1,
+            claim: None,
+        }
+    }
+
+    /// The trigger of another attempt at the build, after it failed because of its agent.
+    pub fn retry(&self, build_id: &str) -> Self {
+        JobTrigger {
+            job_name: self.job_name.clone(),
+            source: format!("retry:{}", build_id),
+            parameters: self.parameters.clone(),
+            revision: self.revision.clone(),
+            attempt: self.attempt + 1,
+            claim: None,
+        }
+    }
+
+    /// The environment variables through which the trigger is passed to the job.
+    pub fn environment(&self) -> BTreeMap<String, String> {
+        let mut environment = self.parameters.clone();
+        if let Some(revision) = &self.revision {
+            environment.insert(
+                format!("{}{}", RESERVED_PARAMETER_PREFIX, REVISION_PARAMETER),
+                revision.clone(),
+            );
+        }
+        environment
+    }
+
+    /// Marks the trigger as invalid, setting the claimed trigger file (if any) aside
+    /// with a `.rejected` suffix, so that it can be inspected.
+    pub fn reject(&self) {
+        if let Some(claim) = &self.claim {
+            set_aside_rejected(claim);
+        }
+    }
+
+    /// Marks the trigger as handled, removing the claimed trigger file (if any)
+    /// so that it is not picked up again after a restart.
+    pub fn acknowledge(&self) {
+        if let Some(claim) = &self.claim {
+            if let Err(remove_err) = fs::remove_file(claim) {
+                warn!(
+                    "Failed to remove claimed trigger file {}: {}",
+                    claim.display(),
+                    remove_err
+                );
+            }
+        }
+    }
+}
+
+/// Watches the queue directory for trigger files and sends the corresponding
+/// triggers to the orchestrator.
+///
+/// Each trigger file is claimed by renaming it to a hidden file before it is sent,
+/// so that it is never handed out twice. The claimed file is only deleted once the
+/// orchestrator acknowledges the trigger, and any claims left over from a previous
+/// run (e.g. after a crash) are sent again when the poller starts.
+///
+/// Cancellation requests, from the `cancel` folder of the queue directory, are sent to
+/// the orchestrator as they are found, and their files removed.
+///
+/// The queue directory and the polling frequency are read from the settings before
+/// every poll, so that changes to them take effect without a restart.
+pub fn launch_job_queue_poller(
+    settings: SharedSettings,
+    jobs: SharedJobs,
+    sender: Sender<JobTrigger>,
+    request_notifier: Sender<OrchestratorRequest>,
+) {
+    thread::spawn(move || {
+        let mut queue_dir = settings.read().unwrap().queue_dir.clone();
+        create_queue_dir(&queue_dir);
+        for stale_claim in list_trigger_files(&queue_dir, true) {
+            if let Some(trigger_file) = trigger_file_from_claim(&stale_claim) {
+                info!(
+                    "Recovering previously claimed trigger {}",
+                    trigger_file.display()
+                );
+                let trigger = match read_claimed_trigger(&queue_dir, &trigger_file, stale_claim) {
+                    Some(trigger) => trigger,
+                    None => continue,
+                };
+                if sender.send(trigger).is_err() {
+                    return;
+                }
+            }
+        }
+        loop {
+            let (new_queue_dir, poll_freq) = {
+                let settings = settings.read().unwrap();
+                (settings.queue_dir.clone(), settings.queue_poll_interval)
+            };
+            if new_queue_dir != queue_dir {
+                info!(
+                    "Watching the new queue directory {}",
+                    new_queue_dir.display()
+                );
+                queue_dir = new_queue_dir;
+                create_queue_dir(&queue_dir);
+            }
+            for trigger_file in list_trigger_files(&queue_dir, false) {
+                let trigger = match claim_trigger_file(&queue_dir, &trigger_file) {
+                    Ok(Some(trigger)) => trigger,
+                    Ok(None) => continue,
+                    Err(claim_err) => {
+                        if claim_err.kind() != io::ErrorKind::NotFound {
+                            warn!(
+                                "Failed to claim trigger file {}: {}",
+                                trigger_file.display(),
+                                claim_err
+                            );
+                        }
+                        continue;
+                    }
+                };
+                info!("Queueing job {}", trigger.job_name);
+                if sender.send(trigger).is_err() {
+                    debug!("Orchestrator is gone, stopping the job queue poller");
+                    return;
+                }
+            }
+            for cancel_file in list_cancel_files(&queue_dir) {
+                if !cancel_build(&queue_dir, &cancel_file, &jobs, &request_notifier) {
+                    debug!("Orchestrator is gone, stopping the job queue poller");
+                    return;
+                }
+            }
+            thread::sleep(poll_freq);
+        }
+    });
+}
+
+fn create_queue_dir(queue_dir: &Path) {
+    if let Err(create_err) = fs::create_dir_all(queue_dir.join(CANCEL_DIR)) {
+        error!(
+            "Failed to create the queue directory {}: {}",
+            queue_dir.display(),
+            create_err
+        );
+    }
+}
+
+/// Lists the cancellation requests of the queue directory, oldest first.
+fn list_cancel_files(queue_dir: &Path) -> Vec<PathBuf> {
+    let cancel_dir = queue_dir.join(CANCEL_DIR);
+    if !cancel_dir.is_dir() {
+        return Vec::new();
+    }
+    list_trigger_files(&cancel_dir, false)
+}
+
+/// Removes the cancellation request, and asks the orchestrator to cancel the build it names.
+/// Returns false if the orchestrator is gone.
+fn cancel_build(
+    queue_dir: &Path,
+    cancel_file: &Path,
+    jobs: &SharedJobs,
+    request_notifier: &Sender<OrchestratorRequest>,
+) -> bool {
+    // removed first, so that the request is handled only once
+    if let Err(remove_err) = fs::remove_file(cancel_file) {
+        if remove_err.kind() != io::ErrorKind::NotFound {
+            warn!(
+                "Failed to remove cancellation request {}: {}",
+                cancel_file.display(),
+                remove_err
+            );
+        }
+        return true;
+    }
+    let target = match relative_name(&queue_dir.join(CANCEL_DIR), cancel_file) {
+        Some(target) => target,
+        None => {
+            error!(
+                "Ignoring cancellation request {}: non-Unicode file name",
+                cancel_file.display()
+            );
+            return true;
+        }
+    };
+    // build ids end with the build number, but so can the names of jobs in folders
+    // (e.g. `releases/2024`), which come first
+    let is_build_id = find_job(jobs, &target).is_none()
+        && target
+            .rsplit('/')
+            .next()
+            .is_some_and(|last_part| last_part.chars().all(|c| c.is_ascii_digit()));
+    if is_build_id {
+        let (reply_notifier, reply_listener) = bounded(1);
+        let request = OrchestratorRequest::CancelBuild {
+            build_id: target.clone(),
+            reply: reply_notifier,
+        };
+        if request_notifier.send(request).is_err() {
+            return false;
+        }
+        if let Ok(false) = reply_listener.recv_timeout(CANCEL_TIMEOUT) {
+            warn!("Not cancelling build {}: it is not running", target);
+        }
+    } else {
+        let (reply_notifier, reply_listener) = bounded(1);
+        let request = OrchestratorRequest::CancelWaitingBuilds {
+            job_name: target.clone(),
+            reply: reply_notifier,
+        };
+        if request_notifier.send(request).is_err() {
+            return false;
+        }
+        if let Ok(0) = reply_listener.recv_timeout(CANCEL_TIMEOUT) {
+            warn!(
+                "Not cancelling job {}: none of its builds is waiting",
+                target
+            );
+        }
+    }
+    true
+}
+
+/// Lists the trigger files in the queue directory and its subfolders, oldest first.
+/// When `claimed` is set, only files already claimed are listed, otherwise only new ones.
+/// The cancellation requests are not listed.
+fn list_trigger_files(queue_dir: &Path, claimed: bool) -> Vec<PathBuf> {
+    let mut trigger_files: Vec<(SystemTime, PathBuf)> = WalkDir::new(queue_dir)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0 || !(is_hidden_folder(entry) || is_cancel_dir(entry))
+        })
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry),
+            Err(walk_err) => {
+                warn!("Failed to list the queue directory: {}", walk_err);
+                None
+            }
+        })
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| {
+            let file_name = entry.file_name().to_str().unwrap_or(".");
+            if claimed {
+                file_name.starts_with('.') && file_name.ends_with(CLAIM_SUFFIX)
+            } else {
+                !file_name.starts_with('.')
+            }
+        })
+        .map(|entry| {
+            let modified = entry
+                .metadata()
+                .ok()
+                .and_then(|metadata| metadata.modified().ok())
+                .unwrap_or(UNIX_EPOCH);
+            (modified, entry.into_path())
+        })
+        .collect();
+    trigger_files.sort();
+    trigger_files.into_iter().map(|(_, path)| path).collect()
+}
+
+fn is_hidden_folder(entry: &DirEntry) -> bool {
+    entry.file_type().is_dir()
+        && entry
+            .file_name()
+            .to_str()
+            .map(|name| name.starts_with('.'))
+            .unwrap_or(true)
+}
+
+fn is_cancel_dir(entry: &DirEntry) -> bool {
+    entry.depth() == 1 && entry.file_type().is_dir() && entry.file_name() == CANCEL_DIR
+}
+
+/// Claims the trigger file, returning `None` if its contents were rejected.
+fn claim_trigger_file(queue_dir: &Path, trigger_file: &Path) -> io::Result<Option<JobTrigger>> {
+    let file_name = trigger_file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "non-Unicode file name"))?;
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let claim = trigger_file.with_file_name(format!(
+        ".{}.{}-{}{}",
+        file_name,
+        since_epoch.as_millis(),
+        CLAIM_COUNTER.fetch_add(1, Ordering::SeqCst),
+        CLAIM_SUFFIX
+    ));
+    fs::rename(trigger_file, &claim)?;
+    Ok(read_claimed_trigger(queue_dir, trigger_file, claim))
+}
+
+/// Parses the parameters of a claimed trigger file. Files that cannot be parsed are
+/// set aside with a `.rejected` suffix, so that they can be inspected.
+fn read_claimed_trigger(
+    queue_dir: &Path,
+    trigger_file: &Path,
+    claim: PathBuf,
+) -> Option<JobTrigger> {
+    let trigger_name = match relative_name(queue_dir, trigger_file) {
+        Some(trigger_name) => trigger_name,
+        None => {
+            error!(
+                "Rejecting trigger file {}: non-Unicode file name",
+                trigger_file.display()
+            );
+            set_aside_rejected(&claim);
+            return None;
+        }
+    };
+    let job_name = job_name_from_trigger(&trigger_name);
+    let parsed_contents = fs::read_to_string(&claim)
+        .map_err(|read_err| read_err.to_string())
+        .and_then(|contents| {
+            parse_parameters(&contents).map_err(|parse_err| parse_err.to_string())
+        });
+    match parsed_contents {
+        Ok(mut parameters) => {
+            let revision = parameters.remove(REVISION_PARAMETER);
+            Some(JobTrigger {
+                job_name,
+                source: format!("queue:{}", trigger_name),
+                parameters,
+                revision,
+                attempt: 1,
+                claim: Some(claim),
+            })
+        }
+        Err(parse_err) => {
+            error!(
+                "Rejecting trigger for {} from {}: {}",
+                job_name,
+                claim.display(),
+                parse_err
+            );
+            set_aside_rejected(&claim);
+            None
+        }
+    }
+}
+
+fn set_aside_rejected(claim: &Path) {
+    let mut rejected = claim.to_path_buf().into_os_string();
+    rejected.push(REJECTED_SUFFIX);
+    if let Err(rename_err) = fs::rename(claim, &rejected) {
+        warn!(
+            "Failed to set aside rejected trigger file {}: {}",
+            claim.display(),
+            rename_err
+        );
+    }
+}
+
+/// Parses `KEY=value` lines into build parameters. Blank lines and lines starting
+/// with `#` are ignored, and keys must be valid environment variable names.
+pub fn parse_parameters(contents: &str) -> Result<BTreeMap<String, String>, ParameterError> {
+    let mut parameters = BTreeMap::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or(ParameterError {
+            line_number,
+            kind: ParameterErrorKind::MissingEquals,
+        })?;
+        let (key, value) = (key.trim(), value.trim());
+        check_parameter_name(key)
+            .and_then(|_| check_parameter_value(key, value))
+            .map_err(|kind| ParameterError { line_number, kind })?;
+        if parameters
+            .insert(key.to_string(), value.to_string())
+            .is_some()
+        {
+            return Err(ParameterError {
+                line_number,
+                kind: ParameterErrorKind::DuplicateName(key.to_string()),
+            });
+        }
+    }
+    Ok(parameters)
+}
+
+/// Checks that a parameter can be exported as an environment variable, and is not reserved.
+pub fn check_parameter_name(name: &str) -> Result<(), ParameterErrorKind> {
+    if !is_valid_parameter_name(name) {
+        return Err(ParameterErrorKind::InvalidName(name.to_string()));
+    }
+    if name.starts_with(RESERVED_PARAMETER_PREFIX) {
+        return Err(ParameterErrorKind::ReservedName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Checks that a parameter value can be sent to the agent, on the line of a `SET` command.
+pub fn check_parameter_value(name: &str, value: &str) -> Result<(), ParameterErrorKind> {
+    if value.contains(['\n', '\r']) {
+        return Err(ParameterErrorKind::MultilineValue(name.to_string()));
+    }
+    Ok(())
+}
+
+fn is_valid_parameter_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_alphabetic() || first == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+/// The job name of a trigger file is its path relative to the queue directory, without
+/// the optional `@` tag (e.g. the trigger `backend/unit_tests@2` is for `backend/unit_tests`).
+fn job_name_from_trigger(trigger_name: &str) -> String {
+    match trigger_name.rsplit_once('/') {
+        Some((folder, file_name)) => format!("{}/{}", folder, untagged(file_name)),
+        None => untagged(trigger_name).to_string(),
+    }
+}
+
+fn untagged(file_name: &str) -> &str {
+    file_name
+        .split(TRIGGER_TAG_SEPARATOR)
+        .next()
+        .unwrap_or(file_name)
+}
+
+/// Recovers the path of the original trigger file from a claimed file, whose name
+/// is of the form `.<trigger file>.<stamp>.claimed`.
+fn trigger_file_from_claim(claim: &Path) -> Option<PathBuf> {
+    let claim_name = claim.file_name()?.to_str()?;
+    let trigger_file_name = claim_name
+        .strip_prefix('.')?
+        .strip_suffix(CLAIM_SUFFIX)?
+        .rsplit_once('.')?
+        .0;
+    Some(claim.with_file_name(trigger_file_name))
+}
+
+#[derive(Debug)]
+pub struct ParameterError {
+    pub line_number: usize,
+    pub kind: ParameterErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ParameterErrorKind {
+    MissingEquals,
+    InvalidName(String),
+    ReservedName(String),
+    DuplicateName(String),
+    MultilineValue(String),
+}
+
+impl fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line_number, self.kind)
+    }
+}
+
+impl fmt::Display for ParameterErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParameterErrorKind::MissingEquals => write!(f, "expected KEY=value"),
+            ParameterErrorKind::InvalidName(name) => {
+                write!(f, "'{}' is not a valid parameter name", name)
+            }
+            ParameterErrorKind::ReservedName(name) => write!(
+                f,
+                "'{}' uses the reserved prefix {}",
+                name, RESERVED_PARAMETER_PREFIX
+            ),
+            ParameterErrorKind::DuplicateName(name) => {
+                write!(f, "'{}' is given more than once", name)
+            }
+            ParameterErrorKind::MultilineValue(name) => {
+                write!(f, "the value of '{}' cannot contain line breaks", name)
+            }
+        }
+    }
+}
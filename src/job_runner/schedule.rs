@@ -0,0 +1,571 @@
+//! Scheduled builds, declared in the `schedule` file of a job folder.
+//!
+//! Each line of the file holds a cron expression with the usual 5 fields (minute, hour,
+//! day of month, month and day of week) or a shortcut such as `@daily`, optionally
+//! followed by:
+//!
+//! * `tz=<timezone>`: the timezone of the expression, e.g. `tz=Europe/Paris` (the local
+//!   timezone of the orchestrator by default).
+//! * `jitter=<delay>`: delays each build by up to that long (e.g. `90s`, `10m` or `1h`, at
+//!   most a day), so that the jobs scheduled at the same time do not all start at once.
+//!
+//! Blank lines and lines starting with `#` are ignored.
+
+use super::queue::JobTrigger;
+use super::settings::{CatchUpPolicy, SharedSettings};
+use super::{request_build, Job, OrchestratorRequest, SharedJobs};
+
+use chrono::{DateTime, Local, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use crossbeam_channel::Sender;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const SCHEDULE: &str = "schedule";
+/// File of the build folder of a job holding the time its schedule was last checked at.
+const LAST_SCHEDULE: &str = ".last_schedule";
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Cron expressions number the days of the week from 0 (or 7) for Sunday.
+const WEEKDAYS: [&str; 8] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+/// The longest jitter accepted, since a longer one would delay builds past the next ones.
+const MAX_JITTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub struct ScheduleEntry {
+    /// The cron expression, as written in the schedule file.
+    pub expression: String,
+    schedule: Schedule,
+    /// The timezone of the expression, or `None` for the local timezone.
+    timezone: Option<Tz>,
+    jitter: Duration,
+}
+
+impl ScheduleEntry {
+    fn parse(line: &str, line_number: usize) -> Result<Self, ScheduleError> {
+        let error = |kind| ScheduleError { line_number, kind };
+        let mut fields = Vec::new();
+        let mut timezone = None;
+        let mut jitter = Duration::from_secs(0);
+        for token in line.split_whitespace() {
+            match token.split_once('=') {
+                Some(("tz", name)) => {
+                    timezone =
+                        Some(Tz::from_str(name).map_err(|_| {
+                            error(ScheduleErrorKind::UnknownTimezone(name.to_string()))
+                        })?)
+                }
+                Some(("jitter", delay)) => {
+                    jitter = parse_delay(delay).ok_or_else(|| {
+                        error(ScheduleErrorKind::InvalidJitter(delay.to_string()))
+                    })?;
+                    if jitter > MAX_JITTER {
+                        return Err(error(ScheduleErrorKind::JitterTooLong(delay.to_string())));
+                    }
+                }
+                Some(_) => return Err(error(ScheduleErrorKind::UnknownOption(token.to_string()))),
+                None => fields.push(token),
+            }
+        }
+        // the `cron` crate expects a field for the seconds, and numbers the days of the week from 1
+        let cron_expression = match fields.as_slice() {
+            [shortcut] if shortcut.starts_with('@') => shortcut.to_string(),
+            [minute, hour, day_of_month, month, day_of_week] => format!(
+                "0 {} {} {} {} {}",
+                minute,
+                hour,
+                day_of_month,
+                month,
+                weekday_names(day_of_week)
+            ),
+            _ => return Err(error(ScheduleErrorKind::WrongFieldCount(fields.len()))),
+        };
+        let schedule = Schedule::from_str(&cron_expression).map_err(|cron_err| {
+            error(ScheduleErrorKind::InvalidExpression(cron_err.to_string()))
+        })?;
+        Ok(ScheduleEntry {
+            expression: fields.join(" "),
+            schedule,
+            timezone,
+            jitter,
+        })
+    }
+
+    /// The times at which the expression matches after `after`, in order.
+    fn times_after(&self, after: DateTime<Utc>) -> Box<dyn Iterator<Item = DateTime<Utc>> + '_> {
+        match self.timezone {
+            Some(timezone) => Box::new(
+                self.schedule
+                    .after(&after.with_timezone(&timezone))
+                    .map(|time| time.with_timezone(&Utc)),
+            ),
+            None => Box::new(
+                self.schedule
+                    .after(&after.with_timezone(&Local))
+                    .map(|time| time.with_timezone(&Utc)),
+            ),
+        }
+    }
+
+    /// The delay of the build scheduled at `time`. It is spread over the jitter like a random
+    /// delay, but it does not change across restarts, so the build is neither lost nor run twice.
+    fn delay(&self, job_name: &str, time: DateTime<Utc>) -> chrono::Duration {
+        if self.jitter.as_secs() == 0 {
+            return chrono::Duration::zero();
+        }
+        let mut key = Vec::new();
+        key.extend_from_slice(job_name.as_bytes());
+        key.push(0);
+        key.extend_from_slice(self.expression.as_bytes());
+        key.push(0);
+        key.extend_from_slice(&time.timestamp().to_le_bytes());
+        chrono::Duration::seconds((fnv1a(&key) % (self.jitter.as_secs() + 1)) as i64)
+    }
+}
+
+/// The 64-bit FNV-1a hash of the bytes. Unlike `DefaultHasher`, it does not change from one
+/// Rust release to the next, which would move the scheduled builds after an upgrade.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+/// Reads the `schedule` file of a job folder, if any. Invalid entries are logged and ignored.
+pub fn read_schedule(job_folder: &Path, job_name: &str) -> Vec<ScheduleEntry> {
+    let contents = match fs::read_to_string(job_folder.join(SCHEDULE)) {
+        Ok(contents) => contents,
+        Err(read_err) if read_err.kind() == io::ErrorKind::NotFound => return Vec::new(),
+        Err(read_err) => {
+            warn!("Failed to read the schedule of {}: {}", job_name, read_err);
+            return Vec::new();
+        }
+    };
+    contents
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(
+            |(line_number, line)| match ScheduleEntry::parse(line, line_number) {
+                Ok(entry) => Some(entry),
+                Err(parse_err) => {
+                    warn!(
+                        "Ignoring an entry of the schedule of {}: {}",
+                        job_name, parse_err
+                    );
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+/// Asks the orchestrator to queue a build whenever a build of a job is due according to its
+/// schedule.
+///
+/// The time at which the schedule of each job was last checked is kept in the build folder
+/// of the job, so that the builds missed while Formica was not running (or refused during a
+/// shutdown) are found on the next start. These are run according to the catch-up policy of the settings. A job that
+/// was never checked before (e.g. a new job) has no missed builds.
+pub fn launch_scheduler(
+    settings: SharedSettings,
+    jobs: SharedJobs,
+    request_notifier: Sender<OrchestratorRequest>,
+) {
+    thread::spawn(move || {
+        let started_at = Utc::now();
+        let mut last_checks: HashMap<String, DateTime<Utc>> = HashMap::new();
+        loop {
+            let now = Utc::now();
+            let (build_dir, catch_up) = {
+                let settings = settings.read().unwrap();
+                (settings.build_dir.clone(), settings.schedule_catch_up)
+            };
+            let scheduled_jobs: Vec<Arc<Job>> = jobs
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|job| !job.schedule.is_empty())
+                .cloned()
+                .collect();
+            last_checks
+                .retain(|job_name, _| scheduled_jobs.iter().any(|job| &job.name == job_name));
+            for job in scheduled_jobs {
+                let state_file = build_dir.join(&job.name).join(LAST_SCHEDULE);
+                let last_check = match last_checks.get(&job.name) {
+                    Some(last_check) => *last_check,
+                    None => read_last_check(&state_file).unwrap_or_else(|| {
+                        write_last_check(&state_file, now);
+                        now
+                    }),
+                };
+                last_checks.insert(job.name.clone(), now);
+                let due_builds = due_builds(&job, last_check, now);
+                if due_builds.is_empty() {
+                    continue;
+                }
+                for entry in apply_catch_up(&job.name, due_builds, started_at, catch_up) {
+                    info!("Scheduling a build of {} ({})", job.name, entry.expression);
+                    let trigger =
+                        JobTrigger::new(&job.name, format!("schedule:{}", entry.expression), None);
+                    match request_build(&request_notifier, trigger) {
+                        Some(true) => (),
+                        // the last check is not recorded, so the build is missed rather than lost
+                        Some(false) => {
+                            debug!("Shutdown in progress, stopping the scheduler");
+                            return;
+                        }
+                        None => {
+                            debug!("Orchestrator is gone, stopping the scheduler");
+                            return;
+                        }
+                    }
+                }
+                write_last_check(&state_file, now);
+            }
+            thread::sleep(CHECK_INTERVAL);
+        }
+    });
+}
+
+/// The builds of the job that became due after `last_check`, up to `now`, in order.
+fn due_builds(
+    job: &Job,
+    last_check: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Vec<(DateTime<Utc>, &ScheduleEntry)> {
+    let mut due_builds = Vec::new();
+    for entry in job.schedule.iter() {
+        // a build scheduled before the last check may still be waiting for its delay
+        let max_delay =
+            chrono::Duration::from_std(entry.jitter).unwrap_or_else(|_| chrono::Duration::zero());
+        for time in entry
+            .times_after(last_check - max_delay)
+            .take_while(|time| *time <= now)
+        {
+            let due_time = time + entry.delay(&job.name, time);
+            if due_time > last_check && due_time <= now {
+                due_builds.push((due_time, entry));
+            }
+        }
+    }
+    due_builds.sort_by_key(|(due_time, _)| *due_time);
+    due_builds
+}
+
+/// Applies the catch-up policy to the builds that were due before the scheduler started.
+fn apply_catch_up<'a>(
+    job_name: &str,
+    due_builds: Vec<(DateTime<Utc>, &'a ScheduleEntry)>,
+    started_at: DateTime<Utc>,
+    policy: CatchUpPolicy,
+) -> Vec<&'a ScheduleEntry> {
+    let (missed_builds, builds): (Vec<_>, Vec<_>) = due_builds
+        .into_iter()
+        .map(|(due_time, entry)| (due_time < started_at, entry))
+        .partition(|(missed, _)| *missed);
+    let mut missed_builds: Vec<&ScheduleEntry> =
+        missed_builds.into_iter().map(|(_, entry)| entry).collect();
+    if !missed_builds.is_empty() {
+        let missed_count = missed_builds.len();
+        match policy {
+            CatchUpPolicy::Skip => missed_builds.clear(),
+            CatchUpPolicy::Once => {
+                missed_builds.drain(..missed_count - 1);
+            }
+            CatchUpPolicy::All => {}
+        }
+        info!(
+            "{} missed {} scheduled build(s) while Formica was not running, catching up with {}",
+            job_name,
+            missed_count,
+            missed_builds.len()
+        );
+    }
+    missed_builds.extend(builds.into_iter().map(|(_, entry)| entry));
+    missed_builds
+}
+
+fn read_last_check(state_file: &Path) -> Option<DateTime<Utc>> {
+    let millis = fs::read_to_string(state_file).ok()?.trim().parse().ok()?;
+    Utc.timestamp_millis_opt(millis).single()
+}
+
+fn write_last_check(state_file: &Path, last_check: DateTime<Utc>) {
+    let written = fs::create_dir_all(state_file.parent().unwrap())
+        .and_then(|_| fs::write(state_file, last_check.timestamp_millis().to_string()));
+    if let Err(write_err) = written {
+        warn!(
+            "Failed to record the schedule check in {}: {}",
+            state_file.display(),
+            write_err
+        );
+    }
+}
+
+/// Replaces the numbers in the day of week field of a cron expression by names, since the
+/// `cron` crate numbers the days from 1 (for Sunday) rather than from 0.
+fn weekday_names(day_of_week: &str) -> String {
+    let weekday = |day: &str| match day.parse::<usize>() {
+        Ok(number) if number < WEEKDAYS.len() => WEEKDAYS[number].to_string(),
+        _ => day.to_string(),
+    };
+    let items: Vec<String> = day_of_week
+        .split(',')
+        .map(|item| {
+            let (days, step) = match item.split_once('/') {
+                Some((days, step)) => (days, Some(step)),
+                None => (item, None),
+            };
+            let days = match days.split_once('-') {
+                // Sunday can end a range as 7, which would come before the start of the range
+                Some((first, "7")) if step.is_none() => format!("{}-Sat,Sun", weekday(first)),
+                Some((first, last)) => format!("{}-{}", weekday(first), weekday(last)),
+                None => weekday(days),
+            };
+            match step {
+                Some(step) => format!("{}/{}", days, step),
+                None => days,
+            }
+        })
+        .collect();
+    items.join(",")
+}
+
+/// Parses a delay such as `90`, `90s`, `10m` or `1h`, or returns `None` if it is invalid or
+/// too long to be represented.
+fn parse_delay(delay: &str) -> Option<Duration> {
+    let (amount, unit_secs) = match delay.char_indices().last()? {
+        (index, 's') => (&delay[..index], 1),
+        (index, 'm') => (&delay[..index], 60),
+        (index, 'h') => (&delay[..index], 60 * 60),
+        _ => (delay, 1),
+    };
+    let secs = amount.parse::<u64>().ok()?.checked_mul(unit_secs)?;
+    Some(Duration::from_secs(secs))
+}
+
+#[derive(Debug)]
+pub struct ScheduleError {
+    pub line_number: usize,
+    pub kind: ScheduleErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ScheduleErrorKind {
+    WrongFieldCount(usize),
+    InvalidExpression(String),
+    UnknownTimezone(String),
+    InvalidJitter(String),
+    JitterTooLong(String),
+    UnknownOption(String),
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ScheduleErrorKind::WrongFieldCount(count) => write!(
+                f,
+                "line {}: expected 5 fields or a shortcut such as @daily, found {} fields",
+                self.line_number, count
+            ),
+            ScheduleErrorKind::InvalidExpression(reason) => write!(
+                f,
+                "line {}: invalid cron expression: {}",
+                self.line_number, reason
+            ),
+            ScheduleErrorKind::UnknownTimezone(name) => {
+                write!(f, "line {}: unknown timezone '{}'", self.line_number, name)
+            }
+            ScheduleErrorKind::InvalidJitter(delay) => write!(
+                f,
+                "line {}: invalid jitter '{}', expected e.g. 90s, 10m or 1h",
+                self.line_number, delay
+            ),
+            ScheduleErrorKind::JitterTooLong(delay) => write!(
+                f,
+                "line {}: jitter '{}' is too long, it can be at most {}h",
+                self.line_number,
+                delay,
+                MAX_JITTER.as_secs() / 3600
+            ),
+            ScheduleErrorKind::UnknownOption(option) => {
+                write!(f, "line {}: unknown option '{}'", self.line_number, option)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(line: &str) -> ScheduleEntry {
+        ScheduleEntry::parse(line, 1).unwrap()
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn delays() {
+        assert_eq!(parse_delay("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_delay("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_delay("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_delay("1h"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_delay(""), None);
+        assert_eq!(parse_delay("h"), None);
+        assert_eq!(parse_delay("-1m"), None);
+        assert_eq!(parse_delay("10d"), None);
+        assert_eq!(parse_delay("18446744073709551615h"), None);
+    }
+
+    #[test]
+    fn jitter_is_bounded() {
+        assert_eq!(entry("@daily jitter=24h").jitter, MAX_JITTER);
+        assert!(matches!(
+            ScheduleEntry::parse("@daily jitter=25h", 3),
+            Err(ScheduleError {
+                line_number: 3,
+                kind: ScheduleErrorKind::JitterTooLong(_)
+            })
+        ));
+        assert!(matches!(
+            ScheduleEntry::parse("@daily jitter=18446744073709551615h", 3),
+            Err(ScheduleError {
+                kind: ScheduleErrorKind::InvalidJitter(_),
+                ..
+            })
+        ));
+        let entry = entry("@daily jitter=1h");
+        let time = utc("2024-01-01T00:00:00Z");
+        let delay = entry.delay("nightly", time);
+        assert!(delay >= chrono::Duration::zero() && delay <= chrono::Duration::hours(1));
+        assert_eq!(delay, entry.delay("nightly", time));
+    }
+
+    #[test]
+    fn delays_are_stable() {
+        // the reference values of FNV-1a
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+        // the same delay with every build of Formica, so that an upgrade moves no build
+        let entry = entry("0 3 * * * jitter=1h");
+        assert_eq!(
+            entry.delay("nightly", utc("2024-01-01T03:00:00Z")),
+            chrono::Duration::seconds(1644)
+        );
+    }
+
+    #[test]
+    fn weekdays() {
+        assert_eq!(weekday_names("*"), "*");
+        assert_eq!(weekday_names("0"), "Sun");
+        assert_eq!(weekday_names("7"), "Sun");
+        assert_eq!(weekday_names("0,6"), "Sun,Sat");
+        assert_eq!(weekday_names("1-5"), "Mon-Fri");
+        assert_eq!(weekday_names("5-7"), "Fri-Sat,Sun");
+        assert_eq!(weekday_names("1-5/2"), "Mon-Fri/2");
+        assert_eq!(weekday_names("*/2"), "*/2");
+        assert_eq!(weekday_names("MON-FRI"), "MON-FRI");
+    }
+
+    #[test]
+    fn expressions() {
+        // 2024-01-01 is a Monday
+        let after = utc("2024-01-01T10:00:00Z");
+        let sundays: Vec<_> = entry("0 4 * * 0 tz=UTC")
+            .times_after(after)
+            .take(2)
+            .collect();
+        assert_eq!(
+            sundays,
+            [utc("2024-01-07T04:00:00Z"), utc("2024-01-14T04:00:00Z")]
+        );
+        let weekdays: Vec<_> = entry("30 8 * * 1-5 tz=UTC")
+            .times_after(utc("2024-01-05T10:00:00Z"))
+            .take(1)
+            .collect();
+        assert_eq!(weekdays, [utc("2024-01-08T08:30:00Z")]);
+        assert!(ScheduleEntry::parse("0 4 * *", 1).is_err());
+        assert!(ScheduleEntry::parse("0 4 * * * tz=Nowhere/Else", 1).is_err());
+        assert!(ScheduleEntry::parse("@daily color=blue", 1).is_err());
+    }
+
+    #[test]
+    fn shortcuts() {
+        let after = utc("2024-01-01T10:00:00Z");
+        let days: Vec<_> = entry("@daily tz=UTC").times_after(after).take(2).collect();
+        assert_eq!(
+            days,
+            [utc("2024-01-02T00:00:00Z"), utc("2024-01-03T00:00:00Z")]
+        );
+        let hours: Vec<_> = entry("@hourly tz=UTC").times_after(after).take(1).collect();
+        assert_eq!(hours, [utc("2024-01-01T11:00:00Z")]);
+        // midnight in Paris is 23:00 UTC in winter
+        let days: Vec<_> = entry("@daily tz=Europe/Paris")
+            .times_after(after)
+            .take(1)
+            .collect();
+        assert_eq!(days, [utc("2024-01-01T23:00:00Z")]);
+    }
+
+    #[test]
+    fn catch_up() {
+        let started_at = utc("2024-01-01T10:00:00Z");
+        let entries = [entry("@daily"), entry("@hourly"), entry("@weekly")];
+        let due_builds = || {
+            vec![
+                (utc("2024-01-01T08:00:00Z"), &entries[0]),
+                (utc("2024-01-01T09:00:00Z"), &entries[1]),
+                (utc("2024-01-01T10:30:00Z"), &entries[2]),
+            ]
+        };
+        let expressions = |entries: Vec<&ScheduleEntry>| -> Vec<String> {
+            entries
+                .iter()
+                .map(|entry| entry.expression.clone())
+                .collect()
+        };
+        assert_eq!(
+            expressions(apply_catch_up(
+                "job",
+                due_builds(),
+                started_at,
+                CatchUpPolicy::Skip
+            )),
+            ["@weekly"]
+        );
+        assert_eq!(
+            expressions(apply_catch_up(
+                "job",
+                due_builds(),
+                started_at,
+                CatchUpPolicy::Once
+            )),
+            ["@hourly", "@weekly"]
+        );
+        assert_eq!(
+            expressions(apply_catch_up(
+                "job",
+                due_builds(),
+                started_at,
+                CatchUpPolicy::All
+            )),
+            ["@daily", "@hourly", "@weekly"]
+        );
+    }
+}
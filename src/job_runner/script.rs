@@ -1,13 +1,60 @@
+use super::build::BuildLogs;
+
+use std::collections::{BTreeMap, BTreeSet};
 use std::fs;
+use std::io;
+#[cfg(feature = "http")]
+use std::io::{Read, Write};
 use std::iter::FromIterator;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
 use std::process::{Child, Command, Output, Stdio};
+use std::sync::Mutex;
+#[cfg(feature = "http")]
+use std::thread;
+#[cfg(feature = "http")]
+use std::time::{Duration, Instant};
+
+/// The process groups of the long-running scripts (workers and cleanups), so that they can
+/// all be killed if Formica has to exit before they finish.
+static PROCESS_GROUPS: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+/// How often a script run with a timeout is checked for its exit.
+#[cfg(feature = "http")]
+const SCRIPT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Like [`find_script`], but for scripts that are not required: `Ok(None)` is returned
+/// when there is no such script.
+pub fn find_optional_script(
+    script_parent: &PathBuf,
+    script_name: &str,
+) -> Result<Option<String>, ScriptError> {
+    let script_exists = fs::read_dir(script_parent)
+        .map(|files| {
+            files.filter_map(|file| file.ok()).any(|file| {
+                file.file_type().map(|ft| ft.is_file()).unwrap_or(false)
+                    && file
+                        .file_name()
+                        .to_str()
+                        .unwrap_or("")
+                        .starts_with(script_name)
+            })
+        })
+        .unwrap_or(false);
+    if !script_exists {
+        return Ok(None);
+    }
+    find_script(script_parent, script_name).map(Some)
+}
 
 pub fn find_script(script_parent: &PathBuf, script_name: &str) -> Result<String, ScriptError> {
-    let files_in_cd = fs::read_dir(script_parent).expect(&format!(
-        "Error while listing files in {}!",
-        script_parent.to_str().unwrap()
-    ));
+    let files_in_cd = fs::read_dir(script_parent).unwrap_or_else(|list_err| {
+        panic!(
+            "Error while listing files in {}! {}",
+            script_parent.to_str().unwrap(),
+            list_err
+        )
+    });
 
     let scripts = Vec::from_iter(files_in_cd.filter(|file| {
         let potential_script = file.as_ref().unwrap();
@@ -56,7 +103,7 @@ pub fn find_script(script_parent: &PathBuf, script_name: &str) -> Result<String,
         });
     }
     Ok(scripts
-        .get(0)
+        .first()
         .unwrap()
         .as_ref()
         .unwrap()
@@ -79,12 +126,12 @@ fn prepare_process(script_path: &PathBuf, script_file: &str) -> Command {
         process = Command::new("cmd");
         process
             .current_dir(script_path)
-            .args(&["/C", &absolute_script_path]);
+            .args(["/C", &absolute_script_path]);
     } else {
         process = Command::new("sh");
         process
             .current_dir(script_path)
-            .args(&["-c", &absolute_script_path]);
+            .args(["-c", &absolute_script_path]);
     }
     process
 }
@@ -93,12 +140,196 @@ pub fn execute_script(script_path: &PathBuf, script_file: &str) -> std::io::Resu
     prepare_process(script_path, script_file).output()
 }
 
-pub fn spawn_worker_script(script_path: &PathBuf, script_file: &str) -> std::io::Result<Child> {
+/// Like [`execute_script`], with additional environment variables.
+pub fn execute_script_with_environment(
+    script_path: &PathBuf,
+    script_file: &str,
+    environment: &BTreeMap<String, String>,
+) -> std::io::Result<Output> {
     prepare_process(script_path, script_file)
+        .envs(environment)
+        .stdin(Stdio::null())
+        .output()
+}
+
+/// Like [`execute_script_with_environment`], with the given input on the standard input.
+/// The script runs in its own process group, which is killed once the script exits (so that
+/// nothing it left behind holds its output open) or runs past `timeout`. Returns `None` if
+/// the script timed out.
+#[cfg(feature = "http")]
+pub fn execute_script_with_input(
+    script_path: &PathBuf,
+    script_file: &str,
+    environment: &BTreeMap<String, String>,
+    input: Vec<u8>,
+    timeout: Duration,
+) -> std::io::Result<Option<Output>> {
+    let mut process = prepare_process(script_path, script_file);
+    process
+        .envs(environment)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = spawn_process_group(process)?;
+    let mut stdin = child.stdin.take().unwrap();
+    // written and read from other threads, since the script may fill its output pipes
+    // before reading all of its input
+    let writer = thread::spawn(move || {
+        // the script does not have to read its input
+        let _ = stdin.write_all(&input);
+    });
+    let stdout_reader = read_to_end_in_background(child.stdout.take().unwrap());
+    let stderr_reader = read_to_end_in_background(child.stderr.take().unwrap());
+    let deadline = Instant::now() + timeout;
+    let exited = loop {
+        match has_exited(&mut child) {
+            Ok(false) if Instant::now() < deadline => thread::sleep(SCRIPT_POLL_INTERVAL),
+            exited => break exited,
+        }
+    };
+    // the script is only reaped once its process group has been killed
+    let killed = kill_process_tree(&mut child);
+    let status = child.wait();
+    release_process_group(&child);
+    let _ = writer.join();
+    let stdout = stdout_reader.join().unwrap_or_default();
+    let stderr = stderr_reader.join().unwrap_or_default();
+    if !exited? {
+        return Ok(None);
+    }
+    killed?;
+    Ok(Some(Output {
+        status: status?,
+        stdout,
+        stderr,
+    }))
+}
+
+#[cfg(feature = "http")]
+fn read_to_end_in_background(
+    mut stream: impl Read + Send + 'static,
+) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut contents = Vec::new();
+        let _ = stream.read_to_end(&mut contents);
+        contents
+    })
+}
+
+/// Spawns the worker of a build in its own process group, see [`kill_process_tree`].
+pub fn spawn_worker_script(
+    script_path: &PathBuf,
+    script_file: &str,
+    environment: &BTreeMap<String, String>,
+) -> std::io::Result<Child> {
+    let mut process = prepare_process(script_path, script_file);
+    process
+        .envs(environment)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
+        .stderr(Stdio::piped());
+    spawn_process_group(process)
+}
+
+/// Spawns a script with its standard output and error going to the logs of a build.
+pub fn spawn_logged_script(
+    script_path: &PathBuf,
+    script_file: &str,
+    environment: &BTreeMap<String, String>,
+    logs: BuildLogs,
+) -> std::io::Result<Child> {
+    let (stdout_log, stderr_log) = logs.into_files();
+    let mut process = prepare_process(script_path, script_file);
+    process
+        .envs(environment)
+        .stdin(Stdio::null())
+        .stdout(stdout_log)
+        .stderr(stderr_log);
+    spawn_process_group(process)
+}
+
+/// Spawns the process as the leader of a new process group, which the processes it starts
+/// (e.g. `ssh` or `docker run`) join unless they create their own.
+fn spawn_process_group(mut process: Command) -> io::Result<Child> {
+    #[cfg(unix)]
+    process.process_group(0);
+    let child = process.spawn()?;
+    PROCESS_GROUPS.lock().unwrap().insert(child.id());
+    Ok(child)
+}
+
+/// Asks the process and everything it started to exit, with SIGTERM. Processes that cannot
+/// be signalled (on Windows) are killed right away.
+#[cfg(unix)]
+pub fn terminate_process_tree(process: &mut Child) -> io::Result<()> {
+    signal_process_group(process.id(), libc::SIGTERM)
+}
+
+#[cfg(not(unix))]
+pub fn terminate_process_tree(process: &mut Child) -> io::Result<()> {
+    process.kill()
+}
+
+/// Kills the process and everything it started, even once the process itself has exited
+/// (as long as it was spawned in its own process group by this module).
+#[cfg(unix)]
+pub fn kill_process_tree(process: &mut Child) -> io::Result<()> {
+    match signal_process_group(process.id(), libc::SIGKILL) {
+        // the whole group is gone already
+        Err(kill_err) if kill_err.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn kill_process_tree(process: &mut Child) -> io::Result<()> {
+    process.kill()
+}
+
+/// Whether the process has exited, without reaping it: as long as it is not waited for,
+/// its pid cannot be reused, so its process group can still be signalled safely.
+#[cfg(target_os = "linux")]
+pub fn has_exited(process: &mut Child) -> io::Result<bool> {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    if unsafe { libc::waitid(libc::P_PID, process.id(), &mut info, options) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // no child has changed state when the pid is left to 0
+    Ok(unsafe { info.si_pid() } != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn has_exited(process: &mut Child) -> io::Result<bool> {
+    process.try_wait().map(|exit_status| exit_status.is_some())
+}
+
+/// Forgets the process group of a process that has been waited for, once nothing is
+/// expected to run in it anymore.
+pub fn release_process_group(process: &Child) {
+    PROCESS_GROUPS.lock().unwrap().remove(&process.id());
+}
+
+/// Kills all the process groups that have not been released, before Formica exits.
+pub fn kill_all_process_groups() {
+    let process_groups = std::mem::take(&mut *PROCESS_GROUPS.lock().unwrap());
+    #[cfg(unix)]
+    for process_group in process_groups {
+        let _ = signal_process_group(process_group, libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    drop(process_groups);
+}
+
+#[cfg(unix)]
+fn signal_process_group(process_group: u32, signal: libc::c_int) -> io::Result<()> {
+    // the id of a process group is the pid of its leader, which is not reused while the
+    // group has members
+    if unsafe { libc::killpg(process_group as libc::pid_t, signal) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
 }
 
 #[derive(Debug)]
@@ -111,3 +342,58 @@ pub enum ScriptErrorKind {
     NoScriptFound,
     TooManyScriptsFound(Vec<String>),
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::{Duration, Instant};
+
+    /// Whether the process is gone, or only waits to be reaped by its new parent.
+    fn is_gone(pid: &str) -> bool {
+        let output = Command::new("ps")
+            .args(["-o", "stat=", "-p", pid])
+            .output()
+            .unwrap();
+        let stat = String::from_utf8_lossy(&output.stdout);
+        stat.trim().is_empty() || stat.trim_start().starts_with('Z')
+    }
+
+    #[test]
+    fn kill_process_tree_kills_the_grandchildren() {
+        let script_dir = std::env::temp_dir().join(format!("formica-tree-{}", std::process::id()));
+        fs::create_dir_all(&script_dir).unwrap();
+        let script_file = script_dir.join("agent_init");
+        fs::write(
+            &script_file,
+            "#!/bin/sh\nsh -c 'sleep 60 & echo $!; wait'\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script_file, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut worker = spawn_worker_script(&script_dir, "agent_init", &BTreeMap::new()).unwrap();
+        let mut grandchild = String::new();
+        BufReader::new(worker.stdout.take().unwrap())
+            .read_line(&mut grandchild)
+            .unwrap();
+        let grandchild = grandchild.trim().to_string();
+        assert!(!is_gone(&grandchild));
+
+        kill_process_tree(&mut worker).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !has_exited(&mut worker).unwrap() {
+            assert!(Instant::now() < deadline, "the worker was not killed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        // not reaped by has_exited
+        assert!(worker.try_wait().unwrap().is_some());
+        release_process_group(&worker);
+        while !is_gone(&grandchild) {
+            assert!(Instant::now() < deadline, "the grandchild was not killed");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_dir_all(&script_dir).unwrap();
+    }
+}
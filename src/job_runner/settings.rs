@@ -0,0 +1,259 @@
+use log::LevelFilter;
+use serde::Deserialize;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Name of the settings file, at the root of the configuration directory.
+pub const SETTINGS_FILE: &str = "formica.toml";
+
+/// The settings of the orchestrator, shared between its threads and replaced
+/// whenever the configuration is updated.
+pub type SharedSettings = Arc<RwLock<Settings>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// How often the `update` script of the configuration is run.
+    pub update_interval: Duration,
+    /// How often the queue directory is checked for new trigger files.
+    pub queue_poll_interval: Duration,
+    /// How often the `poll` scripts of the jobs are run.
+    pub poll_interval: Duration,
+    pub queue_dir: PathBuf,
+    pub build_dir: PathBuf,
+    /// How many builds can run at the same time, or `None` for no limit.
+    pub max_concurrent_jobs: Option<usize>,
+    pub log_level: LevelFilter,
+    /// How long a shutdown requested by SIGTERM waits for the running builds, before
+    /// aborting them.
+    pub shutdown_timeout: Duration,
+    /// What to do with the scheduled builds that were missed while Formica was not running.
+    pub schedule_catch_up: CatchUpPolicy,
+    /// The address the HTTP API listens on (with the `http` feature), e.g. `127.0.0.1:8080`.
+    /// Only read at startup.
+    pub http_address: Option<String>,
+    /// The file holding the secret webhooks are signed with. Requests to the webhooks must
+    /// be signed when it is set.
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub hook_secret_file: Option<PathBuf>,
+    /// The Unix socket the command-line client talks to the orchestrator through. Only read
+    /// at startup.
+    pub control_socket: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CatchUpPolicy {
+    /// Missed builds are not run.
+    Skip,
+    /// A single build is run for each job that missed some.
+    Once,
+    /// Every missed build is run.
+    All,
+}
+
+impl FromStr for CatchUpPolicy {
+    type Err = ();
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "skip" => Ok(CatchUpPolicy::Skip),
+            "once" => Ok(CatchUpPolicy::Once),
+            "all" => Ok(CatchUpPolicy::All),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            update_interval: Duration::from_secs(5 * 60),
+            queue_poll_interval: Duration::from_secs(1),
+            poll_interval: Duration::from_secs(60),
+            queue_dir: PathBuf::from(super::QUEUE_DIR),
+            build_dir: PathBuf::from(super::BUILD_DIR),
+            max_concurrent_jobs: None,
+            log_level: LevelFilter::Info,
+            shutdown_timeout: Duration::from_secs(5 * 60),
+            schedule_catch_up: CatchUpPolicy::Once,
+            http_address: None,
+            hook_secret_file: None,
+            control_socket: PathBuf::from(super::CONTROL_SOCKET),
+        }
+    }
+}
+
+/// The settings file as written by the user, before validation.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SettingsFile {
+    update_interval_secs: Option<u64>,
+    queue_poll_interval_secs: Option<u64>,
+    poll_interval_secs: Option<u64>,
+    queue_dir: Option<PathBuf>,
+    build_dir: Option<PathBuf>,
+    max_concurrent_jobs: Option<usize>,
+    log_level: Option<String>,
+    shutdown_timeout_secs: Option<u64>,
+    schedule_catch_up: Option<String>,
+    http_address: Option<String>,
+    hook_secret_file: Option<PathBuf>,
+    control_socket: Option<PathBuf>,
+}
+
+impl Settings {
+    /// Reads the settings file from the configuration directory, falling back to the
+    /// default settings when there is no such file.
+    pub fn load(config_dir: &Path) -> Result<Settings, SettingsError> {
+        let settings_path = config_dir.join(SETTINGS_FILE);
+        let contents = match fs::read_to_string(&settings_path) {
+            Ok(contents) => contents,
+            Err(read_err) if read_err.kind() == io::ErrorKind::NotFound => {
+                return Ok(Settings::default())
+            }
+            Err(read_err) => {
+                return Err(SettingsError {
+                    kind: SettingsErrorKind::Unreadable(read_err),
+                })
+            }
+        };
+        let settings_file: SettingsFile =
+            toml::from_str(&contents).map_err(|parse_err| SettingsError {
+                kind: SettingsErrorKind::InvalidSyntax(parse_err),
+            })?;
+        let defaults = Settings::default();
+        Ok(Settings {
+            update_interval: positive_duration(
+                "update_interval_secs",
+                settings_file.update_interval_secs,
+                defaults.update_interval,
+            )?,
+            queue_poll_interval: positive_duration(
+                "queue_poll_interval_secs",
+                settings_file.queue_poll_interval_secs,
+                defaults.queue_poll_interval,
+            )?,
+            poll_interval: positive_duration(
+                "poll_interval_secs",
+                settings_file.poll_interval_secs,
+                defaults.poll_interval,
+            )?,
+            queue_dir: non_empty_path("queue_dir", settings_file.queue_dir, defaults.queue_dir)?,
+            build_dir: non_empty_path("build_dir", settings_file.build_dir, defaults.build_dir)?,
+            max_concurrent_jobs: match settings_file.max_concurrent_jobs {
+                Some(0) => return Err(invalid_value("max_concurrent_jobs", "must be at least 1")),
+                max_concurrent_jobs => max_concurrent_jobs,
+            },
+            log_level: match settings_file.log_level {
+                Some(log_level) => LevelFilter::from_str(&log_level).map_err(|_| {
+                    invalid_value(
+                        "log_level",
+                        "must be one of off, error, warn, info, debug or trace",
+                    )
+                })?,
+                None => defaults.log_level,
+            },
+            shutdown_timeout: positive_duration(
+                "shutdown_timeout_secs",
+                settings_file.shutdown_timeout_secs,
+                defaults.shutdown_timeout,
+            )?,
+            schedule_catch_up: match settings_file.schedule_catch_up {
+                Some(policy) => CatchUpPolicy::from_str(&policy).map_err(|_| {
+                    invalid_value("schedule_catch_up", "must be one of skip, once or all")
+                })?,
+                None => defaults.schedule_catch_up,
+            },
+            http_address: match settings_file.http_address {
+                Some(address) if address.trim().is_empty() => {
+                    return Err(invalid_value("http_address", "cannot be empty"))
+                }
+                http_address => http_address,
+            },
+            hook_secret_file: match settings_file.hook_secret_file {
+                Some(path) if path.as_os_str().is_empty() => {
+                    return Err(invalid_value("hook_secret_file", "cannot be empty"))
+                }
+                hook_secret_file => hook_secret_file,
+            },
+            control_socket: non_empty_path(
+                "control_socket",
+                settings_file.control_socket,
+                defaults.control_socket,
+            )?,
+        })
+    }
+
+    /// Applies the log level, unless the level was chosen through the `RUST_LOG` variable.
+    pub fn apply_log_level(&self) {
+        if env::var_os("RUST_LOG").is_none() {
+            log::set_max_level(self.log_level);
+        }
+    }
+}
+
+fn positive_duration(
+    setting: &'static str,
+    seconds: Option<u64>,
+    default: Duration,
+) -> Result<Duration, SettingsError> {
+    match seconds {
+        Some(0) => Err(invalid_value(setting, "must be at least 1 second")),
+        Some(seconds) => Ok(Duration::from_secs(seconds)),
+        None => Ok(default),
+    }
+}
+
+fn non_empty_path(
+    setting: &'static str,
+    path: Option<PathBuf>,
+    default: PathBuf,
+) -> Result<PathBuf, SettingsError> {
+    match path {
+        Some(path) if path.as_os_str().is_empty() => Err(invalid_value(setting, "cannot be empty")),
+        Some(path) => Ok(path),
+        None => Ok(default),
+    }
+}
+
+fn invalid_value(setting: &'static str, reason: &'static str) -> SettingsError {
+    SettingsError {
+        kind: SettingsErrorKind::InvalidValue { setting, reason },
+    }
+}
+
+#[derive(Debug)]
+pub struct SettingsError {
+    pub kind: SettingsErrorKind,
+}
+
+#[derive(Debug)]
+pub enum SettingsErrorKind {
+    Unreadable(io::Error),
+    InvalidSyntax(toml::de::Error),
+    InvalidValue {
+        setting: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            SettingsErrorKind::Unreadable(read_err) => {
+                write!(f, "could not read {}: {}", SETTINGS_FILE, read_err)
+            }
+            SettingsErrorKind::InvalidSyntax(parse_err) => {
+                write!(f, "invalid {}: {}", SETTINGS_FILE, parse_err)
+            }
+            SettingsErrorKind::InvalidValue { setting, reason } => {
+                write!(f, "invalid {} in {}: {}", setting, SETTINGS_FILE, reason)
+            }
+        }
+    }
+}
@@ -1,13 +1,21 @@
+#[cfg(unix)]
+mod client;
 mod job_runner;
 
 use job_runner::InitErrorKind::{
-    InitScriptExecutionError, NoInitScriptFound, NoUpdateScriptInsideConfig,
-    TooManyInitScriptsFound, TooManyUpdateScriptsFound, UpdateScriptExecutionError,
+    ControlSocketError, InitScriptExecutionError, InvalidSettings, NoInitScriptFound, NoJobsFound,
+    NoUpdateScriptInsideConfig, TooManyInitScriptsFound, TooManyUpdateScriptsFound,
+    UpdateScriptExecutionError,
 };
 use job_runner::{ShutdownNotifiers, CONFIG_INIT_PREFIX};
 
 use crossbeam_channel::{select, unbounded, Receiver};
+#[cfg(unix)]
+use signal_hook::consts::{SIGHUP, SIGTERM, SIGUSR1};
+use std::io;
 use std::process::exit;
+#[cfg(unix)]
+use std::thread;
 
 use env_logger::Env;
 #[macro_use]
@@ -22,7 +30,7 @@ fn initialize_jobrunner() -> ShutdownNotifiers {
                     "No job initialization script (starting with '{}') was found!",
                     CONFIG_INIT_PREFIX
                 );
-                eprintln!("If this is your first time, I recommend you run one of the starter tools, e.g.: setup_git");
+                eprintln!("If this is your first time, I recommend you run one of the starter tools: setup_git, setup_hg, setup_fossil, setup_svn or setup_dir");
                 eprintln!("This will setup a scaffold/skeleton jobs configuration ready to populate with new jobs!");
                 exit(exitcode::DATAERR);
             }
@@ -54,7 +62,7 @@ fn initialize_jobrunner() -> ShutdownNotifiers {
                     "No job update script (starting with '{}') was found in the configuration directory!",
                     job_runner::UPDATE
                 );
-                eprintln!("If this is your first time, I recommend you run one of the starter tools, e.g.: setup_git");
+                eprintln!("If this is your first time, I recommend you run one of the starter tools: setup_git, setup_hg, setup_fossil, setup_svn or setup_dir");
                 eprintln!("This will setup a scaffold/skeleton jobs configuration ready to populate with new jobs!");
                 exit(exitcode::DATAERR);
             }
@@ -81,6 +89,36 @@ fn initialize_jobrunner() -> ShutdownNotifiers {
                 );
                 exit(bad_execution.status.code().unwrap_or(exitcode::SOFTWARE));
             }
+            NoJobsFound => {
+                eprintln!(
+                    "No jobs were found in the configuration directory! Each job is a folder with an '{}' script.",
+                    job_runner::AGENT_INIT
+                );
+                exit(exitcode::DATAERR);
+            }
+            InvalidSettings(settings_err) => {
+                eprintln!(
+                    "The settings of the configuration directory are invalid: {}",
+                    settings_err
+                );
+                exit(exitcode::CONFIG);
+            }
+            ControlSocketError(socket_path, socket_err) => {
+                eprintln!(
+                    "Could not listen for commands on {}: {}",
+                    socket_path.display(),
+                    socket_err
+                );
+                exit(exitcode::CANTCREAT);
+            }
+            #[cfg(feature = "http")]
+            job_runner::InitErrorKind::HttpServerError(http_address, server_err) => {
+                eprintln!(
+                    "The HTTP API could not listen on {}: {}",
+                    http_address, server_err
+                );
+                exit(exitcode::UNAVAILABLE);
+            }
         },
     }
 }
@@ -90,7 +128,7 @@ fn explain_exit_logic() {
     info!("Press Ctrl + C again to start a slow shutdown: no new jobs will be accepted, but the existing ones will run their course and only then will Formica shutdown.");
     info!("Press Ctrl + C again to start an immediate shutdown: all jobs will be terminated and the agent machines cleaned up.");
     info!("Press Ctrl + C again to force termination of all agent tracker processes: all the trackers will be terminated without cleaning up the agent machines (at your own risk!)");
-    info!("Press Ctrl + C one more time to exit immediately (very much at your own risk, zombie processes may be left running on the machine).");
+    info!("Press Ctrl + C one more time to exit immediately, killing whatever the workers left running (very much at your own risk!)");
 }
 
 fn build_ctrl_c_channel() -> Result<Receiver<()>, ctrlc::Error> {
@@ -101,8 +139,66 @@ fn build_ctrl_c_channel() -> Result<Receiver<()>, ctrlc::Error> {
     Ok(receiver)
 }
 
+/// Forwards the signals used to run Formica as a service (e.g. by systemd).
+#[cfg(unix)]
+fn build_signal_channel() -> io::Result<Receiver<i32>> {
+    let mut signals = signal_hook::iterator::Signals::new([SIGTERM, SIGHUP, SIGUSR1])?;
+    let (sender, receiver) = unbounded();
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if sender.send(signal).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+#[cfg(not(unix))]
+fn build_signal_channel() -> io::Result<Receiver<i32>> {
+    Ok(crossbeam_channel::never())
+}
+
+#[cfg(unix)]
+fn handle_signal(signal: i32, shutdown_notifiers: &ShutdownNotifiers) {
+    match signal {
+        SIGTERM => {
+            info!("Received SIGTERM");
+            let _ = shutdown_notifiers.graceful_shutdown.send(());
+        }
+        SIGHUP => {
+            info!("Received SIGHUP, updating the configuration...");
+            // a full channel means that an update is already pending
+            let _ = shutdown_notifiers.config_update.try_send(());
+        }
+        SIGUSR1 => {
+            let _ = shutdown_notifiers.state_dump.send(());
+        }
+        _ => {}
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_signal(_signal: i32, _shutdown_notifiers: &ShutdownNotifiers) {}
+
 fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    // with arguments, this is the command-line client of an orchestrator already running
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    if !arguments.is_empty() {
+        #[cfg(unix)]
+        exit(client::run(&arguments));
+        #[cfg(not(unix))]
+        {
+            eprintln!("The command-line client is only supported on Unix");
+            exit(exitcode::UNAVAILABLE);
+        }
+    }
+    // everything is let through env_logger, so that the log level can then be changed
+    // from the settings file (unless RUST_LOG is set)
+    env_logger::Builder::from_env(Env::default().default_filter_or("trace")).init();
+    if std::env::var_os("RUST_LOG").is_none() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
     let ctrl_c_receiver = match build_ctrl_c_channel() {
         Ok(ctrl_c_channel) => ctrl_c_channel,
         Err(ctrl_c_setup_err) => panic!(
@@ -110,6 +206,13 @@ fn main() {
             ctrl_c_setup_err
         ),
     };
+    let signal_receiver = match build_signal_channel() {
+        Ok(signal_channel) => signal_channel,
+        Err(signal_setup_err) => panic!(
+            "There was an error when setting up the signal listener! {:?}",
+            signal_setup_err
+        ),
+    };
     println!("Formica CI is now running");
     explain_exit_logic();
 
@@ -122,18 +225,28 @@ fn main() {
                 number_of_control_c_presses += 1;
                 if number_of_control_c_presses == 1 {
                     info!("Starting slow shutdown: No more jobs will be accepted...");
-                    shutdown_notifiers.slow_shutdown.send(());
+                    let _ = shutdown_notifiers.slow_shutdown.send(());
                 } else if number_of_control_c_presses == 2 {
                     info!("Triggering immediate shutdown: Cleaning up agents...");
-                    shutdown_notifiers.immediate_shutdown.send(());
+                    let _ = shutdown_notifiers.immediate_shutdown.send(());
                 } else if number_of_control_c_presses == 3 {
-                    info!("Forcing termination of all worker tracker processes. Pressing Ctrl+C again may leave zombie processes!");
-                    shutdown_notifiers.force_termination.send(());
+                    info!("Forcing termination of all worker tracker processes. Pressing Ctrl+C again exits right away!");
+                    let _ = shutdown_notifiers.force_termination.send(());
                 } else {
-                    warn!("Terminating immediately! (zombie processes may be left, please restart this machine to clear them up)");
+                    warn!("Terminating immediately! Killing the remaining worker processes (the agent machines are not cleaned up)");
+                    job_runner::kill_all_scripts();
                     exit(exitcode::TEMPFAIL);
                 }
             }
+            recv(signal_receiver) -> signal => {
+                if let Ok(signal) = signal {
+                    handle_signal(signal, &shutdown_notifiers);
+                }
+            }
+            recv(shutdown_notifiers.shutdown_complete) -> _ => {
+                info!("Formica CI has shut down");
+                exit(exitcode::OK);
+            }
         }
     }
 }
@@ -0,0 +1,96 @@
+//! Sets up a new Formica CI node whose job configuration is copied with rsync from a plain
+//! directory, either local or remote (`host:path`).
+//!
+//! Usage: `setup_dir [--example-job] [<directory>]`
+//!
+//! The `update` script mirrors the directory (deleting the files removed from it), so the
+//! new files are copied back to the directory rather than only kept in `formica_conf`.
+
+#[path = "../scaffold.rs"]
+mod scaffold;
+
+use scaffold::{command_line, shell_quote, Scm, CONFIG};
+
+use std::path::Path;
+
+struct Directory;
+
+impl Scm for Directory {
+    const TOOL: &'static str = "setup_dir";
+    const SOURCE: &'static str = "directory";
+    const PUBLISHES: bool = false;
+
+    /// Local directories are made absolute, since the `update` script runs in `formica_conf`.
+    fn resolve_source(&self, source: &str) -> Result<String, String> {
+        let local_path = Path::new(source);
+        if source.contains(':') && !local_path.exists() {
+            return Ok(source.trim_end_matches('/').to_string());
+        }
+        if !local_path.is_dir() {
+            return Err("no such directory".to_string());
+        }
+        local_path
+            .canonicalize()
+            .map_err(|resolve_err| resolve_err.to_string())?
+            .into_os_string()
+            .into_string()
+            .map_err(|_| "the path is not valid UTF-8".to_string())
+    }
+
+    fn fetch_script(&self, source: &str) -> String {
+        format!(
+            "rsync -a --delete {} {}/",
+            shell_quote(&format!("{}/", source)),
+            CONFIG
+        )
+    }
+
+    fn update_script(&self, source: &str) -> String {
+        format!(
+            "rsync -a --delete {} ./",
+            shell_quote(&format!("{}/", source))
+        )
+    }
+
+    fn record(&self, source: &str, files: &[&str]) -> Vec<Vec<String>> {
+        let destination = format!("{}/", source);
+        let mut copy = command_line(&["rsync", "-a", "--relative"], files);
+        copy.push(destination);
+        vec![copy]
+    }
+}
+
+fn main() {
+    scaffold::run(Directory);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts() {
+        assert_eq!(
+            Directory.fetch_script("/srv/ci config"),
+            "rsync -a --delete '/srv/ci config/' formica_conf/"
+        );
+        assert_eq!(
+            Directory.update_script("ci-host:/srv/ci"),
+            "rsync -a --delete 'ci-host:/srv/ci/' ./"
+        );
+    }
+
+    #[test]
+    fn sources() {
+        assert_eq!(
+            Directory.resolve_source("ci-host:/srv/ci/"),
+            Ok("ci-host:/srv/ci".to_string())
+        );
+        assert!(Directory.resolve_source("/no/such/formica/dir").is_err());
+        let current_dir = std::env::current_dir().unwrap();
+        assert_eq!(
+            Directory.resolve_source("."),
+            Ok(current_dir.to_str().unwrap().to_string())
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! Sets up a new Formica CI node whose job configuration is kept in a Fossil repository.
+//!
+//! Usage: `setup_fossil [--example-job] [--push] [<fossil url>]`
+//!
+//! The repository file is cloned next to `formica_conf` (as `formica_conf.fossil`), and
+//! opened in `formica_conf`.
+
+#[path = "../scaffold.rs"]
+mod scaffold;
+
+use scaffold::{command_line, shell_quote, Scm, COMMIT_MESSAGE, CONFIG};
+
+struct Fossil;
+
+impl Scm for Fossil {
+    const TOOL: &'static str = "setup_fossil";
+    const SOURCE: &'static str = "Fossil URL";
+
+    fn fetch_script(&self, source: &str) -> String {
+        format!(
+            "fossil clone {source} {config}.fossil && mkdir {config} && cd {config} && fossil open ../{config}.fossil",
+            source = shell_quote(source),
+            config = CONFIG
+        )
+    }
+
+    fn update_script(&self, _source: &str) -> String {
+        "fossil pull && fossil update".to_string()
+    }
+
+    fn record(&self, _source: &str, files: &[&str]) -> Vec<Vec<String>> {
+        // without --nosync, the commit would be pushed right away when autosync is on
+        vec![
+            command_line(&["fossil", "add"], files),
+            command_line(&["fossil", "commit", "--nosync", "-m", COMMIT_MESSAGE], &[]),
+        ]
+    }
+
+    fn publish(&self, _source: &str) -> Vec<Vec<String>> {
+        vec![command_line(&["fossil", "push"], &[])]
+    }
+}
+
+fn main() {
+    scaffold::run(Fossil);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts() {
+        assert_eq!(
+            Fossil.fetch_script("https://example.com/ci"),
+            "fossil clone 'https://example.com/ci' formica_conf.fossil && mkdir formica_conf \
+             && cd formica_conf && fossil open ../formica_conf.fossil"
+        );
+        assert_eq!(
+            Fossil.update_script("https://example.com/ci"),
+            "fossil pull && fossil update"
+        );
+    }
+}
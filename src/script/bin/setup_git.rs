@@ -1,7 +1,55 @@
-use std::io;
+//! Sets up a new Formica CI node whose job configuration is kept in a Git repository.
+//!
+//! Usage: `setup_git [--example-job] [--push] [<git url>]`
+
+#[path = "../scaffold.rs"]
+mod scaffold;
+
+use scaffold::{command_line, shell_quote, Scm, COMMIT_MESSAGE, CONFIG};
+
+struct Git;
+
+impl Scm for Git {
+    const TOOL: &'static str = "setup_git";
+    const SOURCE: &'static str = "Git URL";
+
+    fn fetch_script(&self, source: &str) -> String {
+        format!("git clone {} {}", shell_quote(source), CONFIG)
+    }
+
+    fn update_script(&self, _source: &str) -> String {
+        "git pull --ff-only".to_string()
+    }
+
+    fn record(&self, _source: &str, files: &[&str]) -> Vec<Vec<String>> {
+        vec![
+            command_line(&["git", "add"], files),
+            command_line(&["git", "commit", "-m", COMMIT_MESSAGE], &[]),
+        ]
+    }
+
+    fn publish(&self, _source: &str) -> Vec<Vec<String>> {
+        vec![command_line(&["git", "push", "origin", "HEAD"], &[])]
+    }
+}
 
 fn main() {
+    scaffold::run(Git);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let reader = io::stdin();
-    // TODO: read URL of Git repo and check it out in formica_conf folder
+    #[test]
+    fn scripts() {
+        assert_eq!(
+            Git.fetch_script("https://example.com/ci's.git"),
+            "git clone 'https://example.com/ci'\\''s.git' formica_conf"
+        );
+        assert_eq!(
+            Git.update_script("https://example.com/ci.git"),
+            "git pull --ff-only"
+        );
+    }
 }
@@ -0,0 +1,55 @@
+//! Sets up a new Formica CI node whose job configuration is kept in a Mercurial repository.
+//!
+//! Usage: `setup_hg [--example-job] [--push] [<mercurial url>]`
+
+#[path = "../scaffold.rs"]
+mod scaffold;
+
+use scaffold::{command_line, shell_quote, Scm, COMMIT_MESSAGE, CONFIG};
+
+struct Mercurial;
+
+impl Scm for Mercurial {
+    const TOOL: &'static str = "setup_hg";
+    const SOURCE: &'static str = "Mercurial URL";
+
+    fn fetch_script(&self, source: &str) -> String {
+        format!("hg clone {} {}", shell_quote(source), CONFIG)
+    }
+
+    fn update_script(&self, _source: &str) -> String {
+        "hg pull --update".to_string()
+    }
+
+    fn record(&self, _source: &str, files: &[&str]) -> Vec<Vec<String>> {
+        vec![
+            command_line(&["hg", "add"], files),
+            command_line(&["hg", "commit", "-m", COMMIT_MESSAGE], &[]),
+        ]
+    }
+
+    fn publish(&self, _source: &str) -> Vec<Vec<String>> {
+        vec![command_line(&["hg", "push"], &[])]
+    }
+}
+
+fn main() {
+    scaffold::run(Mercurial);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts() {
+        assert_eq!(
+            Mercurial.fetch_script("https://example.com/ci"),
+            "hg clone 'https://example.com/ci' formica_conf"
+        );
+        assert_eq!(
+            Mercurial.update_script("https://example.com/ci"),
+            "hg pull --update"
+        );
+    }
+}
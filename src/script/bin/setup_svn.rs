@@ -0,0 +1,55 @@
+//! Sets up a new Formica CI node whose job configuration is kept in a Subversion repository.
+//!
+//! Usage: `setup_svn [--example-job] [--push] [<subversion url>]`
+//!
+//! Subversion commits go straight to the server, so the new files are only added to the
+//! working copy unless `--push` is given.
+
+#[path = "../scaffold.rs"]
+mod scaffold;
+
+use scaffold::{command_line, shell_quote, Scm, COMMIT_MESSAGE, CONFIG};
+
+struct Subversion;
+
+impl Scm for Subversion {
+    const TOOL: &'static str = "setup_svn";
+    const SOURCE: &'static str = "Subversion URL";
+
+    fn fetch_script(&self, source: &str) -> String {
+        format!("svn checkout {} {}", shell_quote(source), CONFIG)
+    }
+
+    fn update_script(&self, _source: &str) -> String {
+        "svn update".to_string()
+    }
+
+    fn record(&self, _source: &str, files: &[&str]) -> Vec<Vec<String>> {
+        vec![command_line(&["svn", "add"], files)]
+    }
+
+    fn publish(&self, _source: &str) -> Vec<Vec<String>> {
+        vec![command_line(&["svn", "commit", "-m", COMMIT_MESSAGE], &[])]
+    }
+}
+
+fn main() {
+    scaffold::run(Subversion);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts() {
+        assert_eq!(
+            Subversion.fetch_script("svn://example.com/ci/trunk"),
+            "svn checkout 'svn://example.com/ci/trunk' formica_conf"
+        );
+        assert_eq!(
+            Subversion.update_script("svn://example.com/ci/trunk"),
+            "svn update"
+        );
+    }
+}
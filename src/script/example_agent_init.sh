@@ -0,0 +1,51 @@
+#!/bin/sh
+# Example agent: runs the steps locally, in the workspace of the build, speaking
+# version 1 of the agent protocol (see the README).
+cd "$FORMICA_WORKSPACE" || exit 1
+step_script=$(mktemp)
+status_file=$(mktemp)
+trap 'rm -f "$step_script" "$status_file"' EXIT
+echo "READY 1"
+while IFS= read -r command; do
+    case "$command" in
+        "RUN "*)
+            line_count=${command##* }
+            : > "$step_script"
+            while [ "$line_count" -gt 0 ]; do
+                IFS= read -r line
+                printf '%s\n' "$line" >> "$step_script"
+                line_count=$((line_count - 1))
+            done
+            # the steps get no input, since the standard input carries the protocol, and
+            # their errors come out through fd 3 to become ERR lines
+            {
+                {
+                    { sh "$step_script" </dev/null 2>&3 3>&- 4>&-; echo $? > "$status_file"; } |
+                        while IFS= read -r line; do printf 'OUT %s\n' "$line"; done
+                } 3>&1 1>&4 4>&- | while IFS= read -r line; do printf 'ERR %s\n' "$line"; done
+            } 4>&1
+            echo "STATUS $(cat "$status_file")"
+            ;;
+        "SET "*)
+            name=${command#SET }
+            name=${name%% *}
+            export "$name=${command#SET $name }"
+            echo "OK"
+            ;;
+        "UPLOAD "*)
+            path=${command#UPLOAD }
+            if [ -f "$path" ]; then
+                base64 "$path" | while IFS= read -r chunk; do echo "DATA $chunk"; done
+                echo "OK"
+            else
+                echo "FAIL no such file: $path"
+            fi
+            ;;
+        EXIT)
+            exit 0
+            ;;
+        *)
+            echo "FAIL unknown command: $command"
+            ;;
+    esac
+done
@@ -0,0 +1,309 @@
+//! The logic shared by the starter tools (`setup_git`, `setup_hg`, ...), which set up a new
+//! Formica CI node whose job configuration is kept in some source control system.
+//!
+//! Each tool writes a `config_init` script fetching the configuration into `formica_conf`,
+//! runs it, and adds an `update` script to the configuration if it has none yet, along with
+//! an optional example job. The new files are then recorded (e.g. committed), and optionally
+//! published (e.g. pushed). Without a source, the settings are asked for interactively.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process::{exit, Command};
+
+pub const CONFIG: &str = "formica_conf";
+const CONFIG_INIT: &str = "config_init";
+const UPDATE: &str = "update";
+const EXAMPLE_JOB: &str = "example";
+const EXAMPLE_AGENT_INIT: &str = include_str!("example_agent_init.sh");
+const EXAMPLE_STEP: &str = "#!/bin/sh\necho \"Hello from Formica CI!\"\n";
+// setup_dir copies the new files instead of committing them
+#[allow(dead_code)]
+pub const COMMIT_MESSAGE: &str = "Add Formica CI configuration";
+
+/// A source control system the job configuration can be kept in.
+pub trait Scm {
+    /// The name of the starter tool, for the usage message.
+    const TOOL: &'static str;
+    /// What the source of the configuration is, e.g. "Git URL".
+    const SOURCE: &'static str;
+    /// Whether recorded files still have to be published to reach the source.
+    const PUBLISHES: bool = true;
+
+    /// Checks the source given by the user, and turns it into the form used by the scripts.
+    fn resolve_source(&self, source: &str) -> Result<String, String> {
+        Ok(source.to_string())
+    }
+    /// The body of the `config_init` script, run next to `formica_conf`.
+    fn fetch_script(&self, source: &str) -> String;
+    /// The body of the `update` script, run inside `formica_conf`.
+    fn update_script(&self, source: &str) -> String;
+    /// The commands recording the new files in `formica_conf`.
+    fn record(&self, source: &str, files: &[&str]) -> Vec<Vec<String>>;
+    /// The commands publishing the recorded files.
+    fn publish(&self, _source: &str) -> Vec<Vec<String>> {
+        Vec::new()
+    }
+}
+
+struct SetupOptions {
+    source: String,
+    example_job: bool,
+    publish: bool,
+}
+
+/// Runs a starter tool for the given source control system.
+pub fn run<S: Scm>(scm: S) {
+    let options = match parse_arguments::<S>() {
+        Some(options) => options,
+        None => {
+            eprintln!("{}", usage::<S>());
+            exit(exitcode::USAGE);
+        }
+    };
+    let source = match scm.resolve_source(&options.source) {
+        Ok(source) => source,
+        Err(reason) => {
+            eprintln!("Invalid {} {}: {}", S::SOURCE, options.source, reason);
+            exit(exitcode::NOINPUT);
+        }
+    };
+    if Path::new(CONFIG).exists() {
+        eprintln!(
+            "There is already a '{}' folder here, nothing to set up!",
+            CONFIG
+        );
+        exit(exitcode::CANTCREAT);
+    }
+    if let Some(init_script) = existing_file(Path::new("."), CONFIG_INIT) {
+        eprintln!(
+            "There is already a '{}' script here ({}), remove it first to start over.",
+            CONFIG_INIT, init_script
+        );
+        exit(exitcode::CANTCREAT);
+    }
+
+    write_script(
+        Path::new(CONFIG_INIT),
+        &format!("#!/bin/sh\n{}\n", scm.fetch_script(&source)),
+    );
+    println!("Created {}, fetching the configuration...", CONFIG_INIT);
+    if !run_command(Command::new("sh").arg(format!("./{}", CONFIG_INIT))) {
+        eprintln!(
+            "Could not fetch the configuration from {}, check the {} and try again.",
+            source,
+            S::SOURCE
+        );
+        let _ = fs::remove_file(CONFIG_INIT);
+        exit(exitcode::UNAVAILABLE);
+    }
+
+    let config_dir = Path::new(CONFIG);
+    let mut added_files = Vec::new();
+    match existing_file(config_dir, UPDATE) {
+        Some(update_script) => println!(
+            "The configuration already has an update script: {}",
+            update_script
+        ),
+        None => {
+            write_script(
+                &config_dir.join(UPDATE),
+                &format!("#!/bin/sh\n{}\n", scm.update_script(&source)),
+            );
+            println!("Created {}/{}", CONFIG, UPDATE);
+            added_files.push(UPDATE);
+        }
+    }
+    if options.example_job {
+        let job_dir = config_dir.join(EXAMPLE_JOB);
+        if job_dir.exists() {
+            println!(
+                "The configuration already has an '{}' folder, skipping the example job",
+                EXAMPLE_JOB
+            );
+        } else {
+            create_dir(&job_dir);
+            write_script(&job_dir.join("agent_init"), EXAMPLE_AGENT_INIT);
+            write_script(&job_dir.join("step_1_hello"), EXAMPLE_STEP);
+            println!("Created the example job {}/{}", CONFIG, EXAMPLE_JOB);
+            added_files.push(EXAMPLE_JOB);
+        }
+    }
+    if added_files.is_empty() {
+        println!("Nothing to record, Formica CI is ready to run.");
+        return;
+    }
+
+    let record_commands = scm.record(&source, &added_files);
+    if !record_commands.iter().all(|command| run_in_config(command)) {
+        eprintln!(
+            "Could not record the new files, please record and publish them from {} yourself.",
+            CONFIG
+        );
+        exit(exitcode::SOFTWARE);
+    }
+    let publish_commands = scm.publish(&source);
+    if !S::PUBLISHES {
+        println!("The new files were recorded.");
+    } else if !options.publish {
+        println!(
+            "The new files were recorded, publish them from {} with: {}",
+            CONFIG,
+            publish_commands
+                .iter()
+                .map(|command| display_command(command))
+                .collect::<Vec<_>>()
+                .join(" && ")
+        );
+    } else if !publish_commands
+        .iter()
+        .all(|command| run_in_config(command))
+    {
+        eprintln!(
+            "Could not publish the new files, please publish them from {} yourself.",
+            CONFIG
+        );
+        exit(exitcode::UNAVAILABLE);
+    }
+    println!("Formica CI is ready to run.");
+}
+
+fn usage<S: Scm>() -> String {
+    format!(
+        "Usage: {} [--example-job]{} [<{}>]",
+        S::TOOL,
+        if S::PUBLISHES { " [--push]" } else { "" },
+        S::SOURCE
+    )
+}
+
+/// Reads the options from the command line, or asks for them when no source is given.
+fn parse_arguments<S: Scm>() -> Option<SetupOptions> {
+    let mut source = None;
+    let mut example_job = false;
+    let mut publish = false;
+    for argument in env::args().skip(1) {
+        match argument.as_str() {
+            "--example-job" => example_job = true,
+            "--push" if S::PUBLISHES => publish = true,
+            _ if argument.starts_with('-') || source.is_some() => return None,
+            _ => source = Some(argument),
+        }
+    }
+    if let Some(source) = source {
+        return Some(SetupOptions {
+            source,
+            example_job,
+            publish,
+        });
+    }
+    let source = prompt(&format!("{} of the job configuration: ", S::SOURCE))?;
+    if source.is_empty() {
+        return None;
+    }
+    Some(SetupOptions {
+        source,
+        example_job: example_job || confirm("Add an example job?")?,
+        publish: publish || (S::PUBLISHES && confirm("Publish the new files?")?),
+    })
+}
+
+fn prompt(question: &str) -> Option<String> {
+    print!("{}", question);
+    io::stdout().flush().ok()?;
+    let mut answer = String::new();
+    match io::stdin().lock().read_line(&mut answer) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(answer.trim().to_string()),
+    }
+}
+
+fn confirm(question: &str) -> Option<bool> {
+    let answer = prompt(&format!("{} [y/N] ", question))?;
+    Some(matches!(answer.to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Returns the name of a file in `dir` starting with `prefix`, the way scripts are looked up.
+fn existing_file(dir: &Path, prefix: &str) -> Option<String> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .find(|file_name| file_name.starts_with(prefix))
+}
+
+/// Quotes a value for a POSIX shell.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// Builds a command line from a program, its arguments and the new files.
+pub fn command_line(arguments: &[&str], files: &[&str]) -> Vec<String> {
+    arguments
+        .iter()
+        .chain(files)
+        .map(|argument| argument.to_string())
+        .collect()
+}
+
+fn display_command(command: &[String]) -> String {
+    command
+        .iter()
+        .map(|argument| {
+            let plain = argument
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./:=@".contains(c));
+            if plain {
+                argument.to_string()
+            } else {
+                shell_quote(argument)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn run_in_config(command: &[String]) -> bool {
+    run_command(
+        Command::new(&command[0])
+            .args(&command[1..])
+            .current_dir(CONFIG),
+    )
+}
+
+fn run_command(command: &mut Command) -> bool {
+    match command.status() {
+        Ok(status) => status.success(),
+        Err(spawn_err) => {
+            eprintln!("Could not run {:?}: {}", command, spawn_err);
+            false
+        }
+    }
+}
+
+fn create_dir(dir: &Path) {
+    if let Err(create_err) = fs::create_dir_all(dir) {
+        eprintln!("Could not create {}: {}", dir.display(), create_err);
+        exit(exitcode::CANTCREAT);
+    }
+}
+
+/// Writes an executable script, since Formica runs the scripts directly.
+fn write_script(path: &Path, contents: &str) {
+    if let Err(write_err) = fs::write(path, contents).and_then(|_| make_executable(path)) {
+        eprintln!("Could not write {}: {}", path.display(), write_err);
+        exit(exitcode::CANTCREAT);
+    }
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,241 @@
+//! Runs the starter tools against local repositories.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static DIR_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A fresh folder for a test, removed when dropped.
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> Self {
+        let dir = env::temp_dir().join(format!(
+            "formica-{}-{}-{}",
+            name,
+            std::process::id(),
+            DIR_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TestDir(dir)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Runs a command in `dir`, with an identity for the commits, failing the test if it fails.
+fn run(dir: &Path, program: &str, arguments: &[&str]) -> Output {
+    let output = Command::new(program)
+        .args(arguments)
+        .current_dir(dir)
+        .env("GIT_AUTHOR_NAME", "Formica Test")
+        .env("GIT_AUTHOR_EMAIL", "test@example.com")
+        .env("GIT_COMMITTER_NAME", "Formica Test")
+        .env("GIT_COMMITTER_EMAIL", "test@example.com")
+        .output()
+        .unwrap_or_else(|spawn_err| panic!("could not run {}: {}", program, spawn_err));
+    assert!(
+        output.status.success(),
+        "{} {:?} failed: {}{}",
+        program,
+        arguments,
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+fn stdout(output: Output) -> String {
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn setup_git_pushes_the_new_files() {
+    let test_dir = TestDir::new("setup-git");
+    let remote = test_dir.path().join("remote.git");
+    let node = test_dir.path().join("node");
+    fs::create_dir(&node).unwrap();
+    run(test_dir.path(), "git", &["init", "--bare", "remote.git"]);
+
+    run(
+        &node,
+        env!("CARGO_BIN_EXE_setup_git"),
+        &["--example-job", "--push", remote.to_str().unwrap()],
+    );
+
+    let config_init = fs::read_to_string(node.join("config_init")).unwrap();
+    assert!(config_init.contains("git clone"), "{}", config_init);
+    assert!(node.join("formica_conf/.git").is_dir());
+    let pushed_files = stdout(run(
+        &remote,
+        "git",
+        &["log", "--all", "--name-only", "--format="],
+    ));
+    assert_eq!(
+        sorted_lines(&pushed_files),
+        ["example/agent_init", "example/step_1_hello", "update"]
+    );
+    let update = fs::read_to_string(node.join("formica_conf/update")).unwrap();
+    assert!(update.contains("git pull --ff-only"), "{}", update);
+}
+
+/// Whether the tool can be run, so that the tests of the other source control systems
+/// are skipped where they are not installed.
+fn installed(program: &str) -> bool {
+    let found = Command::new(program)
+        .arg("--version")
+        .output()
+        .map(|output| output.status.success())
+        .unwrap_or(false);
+    if !found {
+        eprintln!("{} is not installed, skipping the test", program);
+    }
+    found
+}
+
+fn sorted_lines(text: &str) -> Vec<&str> {
+    let mut lines: Vec<&str> = text.lines().filter(|line| !line.is_empty()).collect();
+    lines.sort_unstable();
+    lines
+}
+
+#[test]
+fn setup_dir_copies_the_new_files_back() {
+    if !installed("rsync") {
+        return;
+    }
+    let test_dir = TestDir::new("setup-dir");
+    let source = test_dir.path().join("source");
+    let node = test_dir.path().join("node");
+    fs::create_dir_all(source.join("existing_job")).unwrap();
+    fs::write(source.join("existing_job/agent_init"), "#!/bin/sh\n").unwrap();
+    fs::create_dir(&node).unwrap();
+
+    run(
+        &node,
+        env!("CARGO_BIN_EXE_setup_dir"),
+        &["--example-job", source.to_str().unwrap()],
+    );
+
+    let config_init = fs::read_to_string(node.join("config_init")).unwrap();
+    assert!(config_init.contains("rsync -a --delete"), "{}", config_init);
+    assert!(node.join("formica_conf/existing_job/agent_init").is_file());
+    for new_file in ["update", "example/agent_init", "example/step_1_hello"] {
+        assert!(
+            node.join("formica_conf").join(new_file).is_file(),
+            "{}",
+            new_file
+        );
+        assert!(
+            source.join(new_file).is_file(),
+            "{} was not copied back",
+            new_file
+        );
+    }
+}
+
+#[test]
+fn setup_hg_pushes_the_new_files() {
+    if !installed("hg") {
+        return;
+    }
+    let test_dir = TestDir::new("setup-hg");
+    let remote = test_dir.path().join("remote");
+    let node = test_dir.path().join("node");
+    fs::create_dir(&node).unwrap();
+    run(test_dir.path(), "hg", &["init", "remote"]);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_setup_hg"))
+        .args(["--example-job", "--push", remote.to_str().unwrap()])
+        .current_dir(&node)
+        .env("HGUSER", "Formica Test <test@example.com>")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    assert!(fs::read_to_string(node.join("config_init"))
+        .unwrap()
+        .contains("hg clone"));
+    let pushed_files = stdout(run(&remote, "hg", &["files", "-r", "tip"]));
+    assert_eq!(
+        sorted_lines(&pushed_files),
+        ["example/agent_init", "example/step_1_hello", "update"]
+    );
+}
+
+#[test]
+fn setup_fossil_pushes_the_new_files() {
+    if !installed("fossil") {
+        return;
+    }
+    let test_dir = TestDir::new("setup-fossil");
+    let remote = test_dir.path().join("remote.fossil");
+    let node = test_dir.path().join("node");
+    fs::create_dir(&node).unwrap();
+    run(test_dir.path(), "fossil", &["init", "remote.fossil"]);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_setup_fossil"))
+        .args(["--example-job", "--push", remote.to_str().unwrap()])
+        .current_dir(&node)
+        .env("FOSSIL_USER", "formica")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    assert!(fs::read_to_string(node.join("config_init"))
+        .unwrap()
+        .contains("fossil clone"));
+    let pushed_files = stdout(run(
+        test_dir.path(),
+        "fossil",
+        &["ls", "-R", remote.to_str().unwrap(), "-r", "trunk"],
+    ));
+    assert_eq!(
+        sorted_lines(&pushed_files),
+        ["example/agent_init", "example/step_1_hello", "update"]
+    );
+}
+
+#[test]
+fn setup_svn_commits_the_new_files() {
+    if !installed("svn") || !installed("svnadmin") {
+        return;
+    }
+    let test_dir = TestDir::new("setup-svn");
+    let node = test_dir.path().join("node");
+    fs::create_dir(&node).unwrap();
+    run(test_dir.path(), "svnadmin", &["create", "remote"]);
+    let url = format!("file://{}", test_dir.path().join("remote").display());
+
+    run(
+        &node,
+        env!("CARGO_BIN_EXE_setup_svn"),
+        &["--example-job", "--push", &url],
+    );
+
+    assert!(fs::read_to_string(node.join("config_init"))
+        .unwrap()
+        .contains("svn checkout"));
+    let committed_files = stdout(run(test_dir.path(), "svn", &["ls", "-R", &url]));
+    assert_eq!(
+        sorted_lines(&committed_files),
+        [
+            "example/",
+            "example/agent_init",
+            "example/step_1_hello",
+            "update"
+        ]
+    );
+}